@@ -0,0 +1,38 @@
+use super::error::Result;
+use super::util::{read_u16_be, read_u8};
+
+const ADOBE_IDENTIFIER: &[u8] = b"Adobe";
+
+/// Color transform applied by the encoder, as signaled by the Adobe APP14 segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdobeTransform {
+    /// The components are stored as they are, RGB for three components and CMYK for four.
+    Unknown,
+    /// Three components were transformed from RGB to YCbCr.
+    YCbCr,
+    /// The first three of four components were transformed from CMY to YCbCr, K is stored as it
+    /// is.
+    Ycck,
+}
+
+/// Reads the color transform from the payload of an Adobe APP14 segment.
+///
+/// Returns `None` if the payload doesn't start with the `"Adobe"` identifier. Transform codes
+/// other than 1 and 2 are treated as unknown, like libjpeg does.
+pub fn transform(app14: &[u8]) -> Result<Option<AdobeTransform>> {
+    let mut reader = match app14.strip_prefix(ADOBE_IDENTIFIER) {
+        Some(reader) => reader,
+        None => return Ok(None),
+    };
+
+    // Version and two flag words precede the transform
+    let _version = read_u16_be(&mut reader)?;
+    let _flags0 = read_u16_be(&mut reader)?;
+    let _flags1 = read_u16_be(&mut reader)?;
+    let transform = match read_u8(&mut reader)? {
+        1 => AdobeTransform::YCbCr,
+        2 => AdobeTransform::Ycck,
+        _ => AdobeTransform::Unknown,
+    };
+    Ok(Some(transform))
+}
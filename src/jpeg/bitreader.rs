@@ -0,0 +1,198 @@
+use super::error::{Error, Result};
+use super::util::read_u8;
+use std::io::Read;
+
+/// Reads entropy-coded data bit by bit, most significant bit first.
+///
+/// Stuffed zero bytes after `0xff` are removed (F.1.2.3). A `0xff` followed by anything else is
+/// a marker, which ends the entropy-coded segment. The marker is kept so the caller can handle
+/// it, and no further bits can be read until it is taken with `take_marker`.
+pub struct BitReader<R: Read> {
+    reader: R,
+    // Buffered bits are the lowest `count` bits
+    bits: u32,
+    count: u8,
+    marker: Option<u8>,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            bits: 0,
+            count: 0,
+            marker: None,
+        }
+    }
+
+    pub fn read_bit(&mut self) -> Result<u8> {
+        Ok(self.read_bits(1)? as u8)
+    }
+
+    /// Reads `n` bits, which must be at most 16, into the low bits of the result.
+    pub fn read_bits(&mut self, n: u8) -> Result<u16> {
+        assert!(n <= 16);
+
+        while self.count < n {
+            self.fill()?;
+        }
+        self.count -= n;
+        Ok(((self.bits >> self.count) & ((1 << n) - 1)) as u16)
+    }
+
+    /// Returns the next `n` bits, which must be at most 16, without consuming them. Bits beyond
+    /// the end of the entropy-coded segment read as zero.
+    pub fn peek_bits(&mut self, n: u8) -> Result<u16> {
+        assert!(n <= 16);
+
+        while self.count < n && self.marker.is_none() {
+            match self.fill() {
+                Ok(()) => {}
+                // The marker is kept for the caller
+                Err(_) if self.marker.is_some() => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let bits = if self.count >= n {
+            self.bits >> (self.count - n)
+        } else {
+            self.bits << (n - self.count)
+        };
+        Ok((bits & ((1 << n) - 1)) as u16)
+    }
+
+    /// Consumes `n` bits that were returned by `peek_bits`.
+    pub fn consume(&mut self, n: u8) -> Result<()> {
+        if n > self.count {
+            return Err(Error::Parse("Unexpected marker in entropy-coded data"));
+        }
+        self.count -= n;
+        Ok(())
+    }
+
+    /// Returns whether the next bit starts a new byte.
+    pub fn is_byte_aligned(&self) -> bool {
+        self.count.is_multiple_of(8)
+    }
+
+    /// Discards the remaining bits of the current byte.
+    pub fn align(&mut self) {
+        self.count -= self.count % 8;
+    }
+
+    /// Returns the marker that ended the entropy-coded segment, if it was reached.
+    pub fn marker(&self) -> Option<u8> {
+        self.marker
+    }
+
+    /// Takes the marker that ended the entropy-coded segment, so reading continues with the data
+    /// after it. Bits that were left over before the marker are discarded.
+    pub fn take_marker(&mut self) -> Option<u8> {
+        let marker = self.marker.take();
+        if marker.is_some() {
+            self.count = 0;
+        }
+        marker
+    }
+
+    /// Reads the marker that ends a restart interval, discarding the padding bits in front of it.
+    /// Data that doesn't belong to the interval is skipped until the next marker.
+    pub fn read_marker(&mut self) -> Result<u8> {
+        self.count = 0;
+        while self.marker.is_none() {
+            match self.fill() {
+                Ok(()) => self.count = 0,
+                Err(_) if self.marker.is_some() => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.take_marker().unwrap())
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        if self.marker.is_some() {
+            return Err(Error::Parse("Unexpected marker in entropy-coded data"));
+        }
+
+        let byte = read_u8(&mut self.reader)?;
+        if byte == 0xff {
+            // Markers may be preceded by fill bytes (B.1.1.2)
+            let mut next = read_u8(&mut self.reader)?;
+            while next == 0xff {
+                next = read_u8(&mut self.reader)?;
+            }
+
+            if next != 0x00 {
+                self.marker = Some(next);
+                return Err(Error::Parse("Unexpected marker in entropy-coded data"));
+            }
+        }
+
+        self.bits = (self.bits << 8) | byte as u32;
+        self.count += 8;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_read_most_significant_first() {
+        let data = [0b1011_0010, 0b0111_1111];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(reader.read_bit().unwrap(), 1);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b011);
+        assert!(!reader.is_byte_aligned());
+        assert_eq!(reader.read_bits(6).unwrap(), 0b00_1001);
+        reader.align();
+        assert!(reader.is_byte_aligned());
+        assert!(matches!(reader.read_bits(16), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn stuffed_zero_bytes_are_removed() {
+        let data = [0xff, 0x00, 0x12, 0xff, 0x00];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(reader.read_bits(16).unwrap(), 0xff12);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xff);
+        assert_eq!(reader.marker(), None);
+    }
+
+    #[test]
+    fn fill_bytes_before_a_marker_are_skipped() {
+        let data = [0xab, 0xff, 0xff, 0xff, 0xd3, 0x42];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xab);
+        assert!(matches!(reader.read_bit(), Err(Error::Parse(_))));
+        assert_eq!(reader.marker(), Some(0xd3));
+        // No bits can be read until the marker is taken
+        assert!(reader.read_bit().is_err());
+        assert_eq!(reader.take_marker(), Some(0xd3));
+        assert_eq!(reader.read_bits(8).unwrap(), 0x42);
+    }
+
+    #[test]
+    fn peeking_past_a_marker_pads_with_zeros() {
+        let data = [0xa5, 0xff, 0xd9];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(reader.peek_bits(16).unwrap(), 0xa500);
+        assert_eq!(reader.marker(), Some(0xd9));
+        reader.consume(4).unwrap();
+        assert_eq!(reader.peek_bits(8).unwrap(), 0x50);
+        reader.consume(4).unwrap();
+        // The padding isn't part of the data
+        assert!(reader.consume(1).is_err());
+    }
+
+    #[test]
+    fn read_marker_skips_padding_and_stray_data() {
+        let data = [0b1010_1111, 0x12, 0xff, 0xd5, 0x34];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(reader.read_bits(4).unwrap(), 0b1010);
+        assert_eq!(reader.read_marker().unwrap(), 0xd5);
+        assert_eq!(reader.read_bits(8).unwrap(), 0x34);
+    }
+}
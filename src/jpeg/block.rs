@@ -0,0 +1,69 @@
+use super::decoder::QuantizationTable;
+
+/// Row-major index of each coefficient of an 8x8 block in zig-zag order (Figure A.6).
+#[rustfmt::skip]
+pub const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Reorders a block from zig-zag order, as stored in the bitstream, to row-major order.
+pub fn dezigzag(input: &[i32; 64]) -> [i32; 64] {
+    let mut output = [0; 64];
+    for (i, value) in input.iter().enumerate() {
+        output[ZIGZAG[i]] = *value;
+    }
+    output
+}
+
+/// Multiplies each coefficient of a row-major block by its quantization step (A.3.4).
+pub fn dequantize(block: &mut [i32; 64], table: &QuantizationTable) {
+    for (coefficient, step) in block.iter_mut().zip(table) {
+        // Only corrupt coefficients can overflow, which must not panic
+        *coefficient = coefficient.wrapping_mul(*step as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zigzag_starts_right_then_down() {
+        // (row, column) of the first coefficients in zig-zag order
+        let position = |i: usize| (ZIGZAG[i] / 8, ZIGZAG[i] % 8);
+        assert_eq!(position(0), (0, 0));
+        assert_eq!(position(1), (0, 1));
+        assert_eq!(position(2), (1, 0));
+        assert_eq!(position(63), (7, 7));
+    }
+
+    #[test]
+    fn dezigzag_is_a_permutation() {
+        let input: [i32; 64] = std::array::from_fn(|i| i as i32);
+        let output = dezigzag(&input);
+        assert_eq!(output[1], 1);
+        assert_eq!(output[8], 2);
+        let mut sorted = output;
+        sorted.sort();
+        assert_eq!(sorted, input);
+    }
+
+    #[test]
+    fn dc_only_block_scales_by_first_step() {
+        let table: QuantizationTable = (16..80).collect();
+        let mut block = [0; 64];
+        block[0] = -7;
+        dequantize(&mut block, &table);
+
+        let mut expected = [0; 64];
+        expected[0] = -7 * 16;
+        assert_eq!(block, expected);
+    }
+}
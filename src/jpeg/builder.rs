@@ -0,0 +1,319 @@
+use super::block::ZIGZAG;
+use super::tables::{
+    STD_AC_CHROMA_BITS, STD_AC_CHROMA_VALUES, STD_AC_LUMA_BITS, STD_AC_LUMA_VALUES,
+    STD_DC_CHROMA_BITS, STD_DC_CHROMA_VALUES, STD_DC_LUMA_BITS, STD_DC_LUMA_VALUES,
+};
+
+/// Returns the coefficients of a block, given the component index and the block column and line
+/// in the component.
+type CoefficientFn = Box<dyn Fn(usize, usize, usize) -> [i32; 64]>;
+
+/// Builds minimal baseline JPEG streams from quantized coefficients, so tests can craft the
+/// exact files they need without binary fixtures.
+///
+/// The first component uses the luminance tables of Annex K, all others the chrominance tables.
+/// The quantization tables are all ones by default, so a block whose only coefficient is a DC
+/// of `8 * (value - 128)` decodes to samples of `value`.
+pub struct JpegBuilder {
+    width: u16,
+    height: u16,
+    // Horizontal and vertical sampling factor of each component
+    sampling_factors: Vec<(u8, u8)>,
+    quantization_table: [u8; 64],
+    restart_interval: u16,
+    coefficients: CoefficientFn,
+}
+
+impl JpegBuilder {
+    /// Starts a grayscale image of `width` x `height` pixels where every block is zero, which
+    /// decodes to samples of 128.
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            sampling_factors: vec![(1, 1)],
+            quantization_table: [1; 64],
+            restart_interval: 0,
+            coefficients: Box::new(|_, _, _| [0; 64]),
+        }
+    }
+
+    /// Sets the horizontal and vertical sampling factors of each component, which also sets the
+    /// number of components. Frames with more than one component use a single interleaved scan.
+    pub fn sampling_factors(mut self, sampling_factors: &[(u8, u8)]) -> Self {
+        self.sampling_factors = sampling_factors.to_vec();
+        self
+    }
+
+    /// Sets the quantization table shared by all components, in row-major order.
+    pub fn quantization_table(mut self, quantization_table: [u8; 64]) -> Self {
+        self.quantization_table = quantization_table;
+        self
+    }
+
+    /// Inserts restart markers every `restart_interval` MCUs.
+    pub fn restart_interval(mut self, restart_interval: u16) -> Self {
+        self.restart_interval = restart_interval;
+        self
+    }
+
+    /// Sets the quantized coefficients of the blocks, in row-major order. `coefficients` is
+    /// called with the component index and the block column and line in the component.
+    pub fn coefficients(
+        mut self,
+        coefficients: impl Fn(usize, usize, usize) -> [i32; 64] + 'static,
+    ) -> Self {
+        self.coefficients = Box::new(coefficients);
+        self
+    }
+
+    /// Returns the complete stream, from SOI to EOI.
+    pub fn build(&self) -> Vec<u8> {
+        let mut output = vec![0xff, 0xd8];
+
+        let mut dqt = vec![0x00];
+        dqt.extend(ZIGZAG.iter().map(|i| self.quantization_table[*i]));
+        push_segment(&mut output, 0xdb, &dqt);
+
+        let mut sof = vec![8];
+        sof.extend(self.height.to_be_bytes());
+        sof.extend(self.width.to_be_bytes());
+        sof.push(self.sampling_factors.len() as u8);
+        for (i, (horizontal, vertical)) in self.sampling_factors.iter().enumerate() {
+            sof.extend([i as u8 + 1, horizontal << 4 | vertical, 0]);
+        }
+        push_segment(&mut output, 0xc0, &sof);
+
+        let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+            (0x00, &STD_DC_LUMA_BITS, &STD_DC_LUMA_VALUES),
+            (0x10, &STD_AC_LUMA_BITS, &STD_AC_LUMA_VALUES),
+            (0x01, &STD_DC_CHROMA_BITS, &STD_DC_CHROMA_VALUES),
+            (0x11, &STD_AC_CHROMA_BITS, &STD_AC_CHROMA_VALUES),
+        ];
+        for (table_info, bits, values) in tables {
+            let mut dht = vec![table_info];
+            dht.extend(bits);
+            dht.extend(values);
+            push_segment(&mut output, 0xc4, &dht);
+        }
+
+        if self.restart_interval != 0 {
+            push_segment(&mut output, 0xdd, &self.restart_interval.to_be_bytes());
+        }
+
+        let mut sos = vec![self.sampling_factors.len() as u8];
+        for i in 0..self.sampling_factors.len() {
+            let table = (i > 0) as u8;
+            sos.extend([i as u8 + 1, table << 4 | table]);
+        }
+        sos.extend([0, 63, 0]);
+        push_segment(&mut output, 0xda, &sos);
+
+        output.extend(self.scan_data());
+        output.extend([0xff, 0xd9]);
+        output
+    }
+
+    fn scan_data(&self) -> Vec<u8> {
+        let luma = (
+            codes(&STD_DC_LUMA_BITS, &STD_DC_LUMA_VALUES),
+            codes(&STD_AC_LUMA_BITS, &STD_AC_LUMA_VALUES),
+        );
+        let chroma = (
+            codes(&STD_DC_CHROMA_BITS, &STD_DC_CHROMA_VALUES),
+            codes(&STD_AC_CHROMA_BITS, &STD_AC_CHROMA_VALUES),
+        );
+
+        let max_horizontal = self.sampling_factors.iter().map(|f| f.0).max().unwrap_or(1);
+        let max_vertical = self.sampling_factors.iter().map(|f| f.1).max().unwrap_or(1);
+        let width = self.width as usize;
+        let height = self.height as usize;
+
+        // Blocks of each MCU as component index, block column and block line (A.2)
+        let mut mcus: Vec<Vec<(usize, usize, usize)>> = Vec::new();
+        if let [(horizontal, vertical)] = self.sampling_factors[..] {
+            let blocks_per_line = (width * horizontal as usize)
+                .div_ceil(max_horizontal as usize)
+                .div_ceil(8);
+            let block_lines = (height * vertical as usize)
+                .div_ceil(max_vertical as usize)
+                .div_ceil(8);
+            for y in 0..block_lines {
+                mcus.extend((0..blocks_per_line).map(|x| vec![(0, x, y)]));
+            }
+        } else {
+            let mcus_per_line = width.div_ceil(8 * max_horizontal as usize);
+            let mcu_lines = height.div_ceil(8 * max_vertical as usize);
+            for mcu_y in 0..mcu_lines {
+                for mcu_x in 0..mcus_per_line {
+                    let mut blocks = Vec::new();
+                    for (i, (horizontal, vertical)) in self.sampling_factors.iter().enumerate() {
+                        let (horizontal, vertical) = (*horizontal as usize, *vertical as usize);
+                        for v in 0..vertical {
+                            for h in 0..horizontal {
+                                blocks.push((i, mcu_x * horizontal + h, mcu_y * vertical + v));
+                            }
+                        }
+                    }
+                    mcus.push(blocks);
+                }
+            }
+        }
+
+        let mut writer = BitWriter::default();
+        let mut predictors = vec![0; self.sampling_factors.len()];
+        for (i, blocks) in mcus.iter().enumerate() {
+            let interval = self.restart_interval as usize;
+            if interval != 0 && i != 0 && i % interval == 0 {
+                writer.flush();
+                writer
+                    .output
+                    .extend([0xff, 0xd0 + ((i / interval - 1) % 8) as u8]);
+                predictors.fill(0);
+            }
+
+            for (component, x, y) in blocks {
+                let (dc_codes, ac_codes) = if *component == 0 { &luma } else { &chroma };
+                let block = (self.coefficients)(*component, *x, *y);
+                let zigzag: Vec<i32> = ZIGZAG.iter().map(|i| block[*i]).collect();
+
+                let difference = zigzag[0] - predictors[*component];
+                predictors[*component] = zigzag[0];
+                let (category, bits) = category_and_bits(difference);
+                writer.write(dc_codes[category as usize]);
+                writer.write((bits, category));
+
+                let mut run = 0;
+                for coefficient in &zigzag[1..] {
+                    if *coefficient == 0 {
+                        run += 1;
+                        continue;
+                    }
+                    while run > 15 {
+                        writer.write(ac_codes[0xf0]);
+                        run -= 16;
+                    }
+                    let (category, bits) = category_and_bits(*coefficient);
+                    writer.write(ac_codes[(run << 4 | category) as usize]);
+                    writer.write((bits, category));
+                    run = 0;
+                }
+                if run > 0 {
+                    writer.write(ac_codes[0x00]);
+                }
+            }
+        }
+        writer.flush();
+        writer.output
+    }
+}
+
+fn push_segment(output: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    output.extend([0xff, marker]);
+    output.extend((payload.len() as u16 + 2).to_be_bytes());
+    output.extend(payload);
+}
+
+/// Returns the code and code length of every value of a Huffman table, indexed by value
+/// (C.2).
+fn codes(bits: &[u8; 16], values: &[u8]) -> Vec<(u16, u8)> {
+    let mut codes = vec![(0, 0); 256];
+    let mut values = values.iter();
+    let mut code = 0u16;
+    for (i, count) in bits.iter().enumerate() {
+        for value in values.by_ref().take(*count as usize) {
+            codes[*value as usize] = (code, i as u8 + 1);
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+/// Returns the number of additional bits of a coefficient and the bits themselves, the inverse
+/// of `entropy::extend`.
+fn category_and_bits(value: i32) -> (u8, u16) {
+    let category = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 {
+        value + (1 << category) - 1
+    } else {
+        value
+    };
+    (category, bits as u16)
+}
+
+#[derive(Default)]
+struct BitWriter {
+    output: Vec<u8>,
+    bits: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    /// Writes the `length` low bits of `code`, most significant bit first.
+    fn write(&mut self, (code, length): (u16, u8)) {
+        for i in (0..length).rev() {
+            self.bits = (self.bits << 1) | ((code >> i) & 1) as u32;
+            self.count += 1;
+            if self.count == 8 {
+                let byte = self.bits as u8;
+                self.output.push(byte);
+                // Stuff a zero byte, so the data can't be mistaken for a marker (F.1.2.3)
+                if byte == 0xff {
+                    self.output.push(0x00);
+                }
+                self.bits = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Pads the last byte with one bits.
+    fn flush(&mut self) {
+        if self.count > 0 {
+            let padding = 8 - self.count;
+            self.write(((1 << padding) - 1, padding));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::decode_bytes;
+    use crate::jpeg::image::PixelFormat;
+
+    /// Returns a block whose samples all decode to `value` with the default quantization table.
+    fn flat_block(value: u8) -> [i32; 64] {
+        let mut block = [0; 64];
+        block[0] = 8 * (value as i32 - 128);
+        block
+    }
+
+    #[test]
+    fn grayscale_blocks_decode_to_their_dc_values() {
+        let data = JpegBuilder::new(16, 16)
+            .coefficients(|_, x, y| flat_block([[10, 90], [170, 250]][y][x]))
+            .build();
+
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!((image.width, image.height), (16, 16));
+        assert_eq!(image.format, PixelFormat::Grayscale);
+        for (i, pixel) in image.pixels.iter().enumerate() {
+            let (x, y) = (i % 16 / 8, i / 16 / 8);
+            assert_eq!(*pixel, [[10, 90], [170, 250]][y][x], "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn ycbcr_blocks_decode_to_rgb() {
+        // Y = 128 with neutral chroma is mid gray
+        let data = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1), (1, 1), (1, 1)])
+            .build();
+
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!(image.format, PixelFormat::Rgb);
+        assert_eq!(image.pixels, vec![128; 8 * 8 * 3]);
+    }
+}
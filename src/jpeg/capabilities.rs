@@ -0,0 +1,53 @@
+/// Features supported by this build of the decoder.
+///
+/// Lets tools check up front whether decoding a file is worth attempting, instead of relying on
+/// `Error::NotImplemented` after parsing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Baseline sequential DCT (SOF0).
+    pub baseline: bool,
+    /// Extended sequential DCT with Huffman coding (SOF1).
+    pub extended_sequential: bool,
+    /// Progressive DCT (SOF2).
+    pub progressive: bool,
+    /// Lossless coding (SOF3).
+    pub lossless: bool,
+    /// Arithmetic instead of Huffman entropy coding (SOF9 and up).
+    pub arithmetic_coding: bool,
+    /// Hierarchical mode with differential frames (SOF5 to SOF7, SOF13 to SOF15).
+    pub hierarchical: bool,
+    /// Supported sample precisions in bits.
+    pub precisions: &'static [u8],
+    /// Components with smaller sampling factors than the largest ones in the frame.
+    pub chroma_subsampling: bool,
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        baseline: true,
+        extended_sequential: true,
+        progressive: false,
+        lossless: false,
+        arithmetic_coding: false,
+        hierarchical: false,
+        precisions: &[8],
+        chroma_subsampling: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::builder::JpegBuilder;
+    use crate::jpeg::decoder::decode_bytes;
+
+    #[test]
+    fn baseline_is_always_supported() {
+        let capabilities = capabilities();
+        assert!(capabilities.baseline);
+        assert!(capabilities.precisions.contains(&8));
+
+        // The builder writes baseline frames with 8-bit samples
+        assert!(decode_bytes(&JpegBuilder::new(8, 8).build()).is_ok());
+    }
+}
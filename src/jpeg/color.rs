@@ -0,0 +1,96 @@
+// Coefficients of the conversion from JFIF 1.02 (BT.601 with full-range samples), in fixed
+// point with 16 fractional bits
+const CR_TO_R: i32 = 91881; // 1.402
+const CB_TO_G: i32 = 22554; // 0.344136
+const CR_TO_G: i32 = 46802; // 0.714136
+const CB_TO_B: i32 = 116130; // 1.772
+
+const FRACTIONAL_BITS: u32 = 16;
+
+/// Converts a YCbCr sample to RGB as defined by JFIF, where all components use the full range of
+/// 8-bit samples and the chroma components are centered at 128.
+pub fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let y = (y as i32) << FRACTIONAL_BITS;
+    let cb = cb as i32 - 128;
+    let cr = cr as i32 - 128;
+
+    // Round to nearest before dropping the fractional bits
+    let to_sample = |value: i32| {
+        ((value + (1 << (FRACTIONAL_BITS - 1))) >> FRACTIONAL_BITS).clamp(0, 255) as u8
+    };
+    [
+        to_sample(y + CR_TO_R * cr),
+        to_sample(y - CB_TO_G * cb - CR_TO_G * cr),
+        to_sample(y + CB_TO_B * cb),
+    ]
+}
+
+// Weights of the color components in the luma of JFIF 1.02, in the same fixed point
+const R_TO_Y: i32 = 19595; // 0.299
+const G_TO_Y: i32 = 38470; // 0.587
+const B_TO_Y: i32 = 7471; // 0.114
+
+/// Converts interleaved RGB samples to grayscale, using the luma weights of the YCbCr
+/// conversion.
+pub fn rgb_to_grayscale(rgb: &[u8]) -> Vec<u8> {
+    rgb.chunks_exact(3)
+        .map(|pixel| {
+            let luma =
+                R_TO_Y * pixel[0] as i32 + G_TO_Y * pixel[1] as i32 + B_TO_Y * pixel[2] as i32;
+            ((luma + (1 << (FRACTIONAL_BITS - 1))) >> FRACTIONAL_BITS) as u8
+        })
+        .collect()
+}
+
+/// Swaps the red and blue samples of interleaved RGB samples, which also converts BGR to RGB.
+pub fn rgb_to_bgr(rgb: &[u8]) -> Vec<u8> {
+    rgb.chunks_exact(3)
+        .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+        .collect()
+}
+
+/// Converts interleaved CMYK samples, where 0 means no ink, to RGB.
+///
+/// This is a naive conversion without color management, which is good enough for previews.
+pub fn cmyk_to_rgb(cmyk: &[u8]) -> Vec<u8> {
+    cmyk.chunks_exact(4)
+        .flat_map(|pixel| {
+            let white = 255 - pixel[3] as u32;
+            // Divide by 255 with rounding
+            let scale = |ink: u8| (((255 - ink as u32) * white + 127) / 255) as u8;
+            [scale(pixel[0]), scale(pixel[1]), scale(pixel[2])]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grayscale_uses_the_luma_weights() {
+        let rgb = [0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+        assert_eq!(rgb_to_grayscale(&rgb), [0, 255, 76, 150, 29]);
+    }
+
+    #[test]
+    fn bgr_swaps_red_and_blue() {
+        assert_eq!(rgb_to_bgr(&[1, 2, 3, 4, 5, 6]), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(rgb_to_bgr(&rgb_to_bgr(&[1, 2, 3])), [1, 2, 3]);
+    }
+
+    #[test]
+    fn cmyk_ink_darkens_rgb() {
+        let cmyk = [
+            0, 0, 0, 0, // no ink
+            255, 255, 255, 0, // full CMY
+            0, 0, 0, 255, // full black
+            0, 128, 255, 0, // half magenta, full yellow
+            0, 0, 0, 128, // half black
+        ];
+        assert_eq!(
+            cmyk_to_rgb(&cmyk),
+            [255, 255, 255, 0, 0, 0, 0, 0, 0, 255, 127, 0, 127, 127, 127]
+        );
+    }
+}
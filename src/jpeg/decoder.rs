@@ -1,18 +1,166 @@
-use super::error::{Error, Result};
-use super::huffman_tree::HuffmanTree;
+use super::adobe::{self, AdobeTransform};
+use super::bitreader::BitReader;
+use super::block::{dequantize, dezigzag, ZIGZAG};
+use super::color::ycbcr_to_rgb;
+use super::entropy::{decode_ac_block, decode_dc_difference};
+use super::error::{Context, Error, Result};
+use super::exif;
+use super::huffman_tree::{FastHuffman, HuffmanTree};
+use super::idct::idct_8x8;
+use super::image::{DecodedImage, PixelFormat, ScanLayout};
+use super::jfif::{self, JfifHeader};
 use super::marker::Marker;
+use super::mpf;
+use super::reader::PushbackReader;
+use super::structure::JpegStructure;
+use super::upsampler::{NearestUpsampler, Upsampler};
 use super::util::{read_u16_be, read_u8};
+use std::borrow::Cow;
 use std::io;
 use std::io::Read;
+use std::ops::Range;
 
 struct Image {
     frame_header: Option<FrameHeader>,
     scans: Vec<Scan>,
     quantization_tables: [Option<QuantizationTable>; 4],
-    ac_huffman_tables: [Option<HuffmanTree>; 4],
-    dc_huffman_tables: [Option<HuffmanTree>; 4],
+    // Lookup tables of the Huffman tables, built when the DHT segment is parsed
+    ac_huffman_tables: [Option<FastHuffman>; 4],
+    dc_huffman_tables: [Option<FastHuffman>; 4],
+    // Number of MCUs between restart markers, 0 if restart markers aren't used
+    restart_interval: u16,
+    // Decoded sample planes, in the order of the frame components
+    components: Vec<Component>,
+    // Color transform of the Adobe APP14 segment, if there is one
+    adobe_transform: Option<AdobeTransform>,
 }
 
+impl Image {
+    fn new() -> Self {
+        Self {
+            frame_header: None,
+            scans: Vec::new(),
+            quantization_tables: [None, None, None, None],
+            dc_huffman_tables: [None, None, None, None],
+            ac_huffman_tables: [None, None, None, None],
+            restart_interval: 0,
+            components: Vec::new(),
+            adobe_transform: None,
+        }
+    }
+
+    /// Starts the next image of a stream in abbreviated format, which takes over the tables of
+    /// this one (B.4). Tables may be redefined by the next image.
+    fn take_tables(&mut self) -> Self {
+        Self {
+            quantization_tables: std::mem::take(&mut self.quantization_tables),
+            dc_huffman_tables: std::mem::take(&mut self.dc_huffman_tables),
+            ac_huffman_tables: std::mem::take(&mut self.ac_huffman_tables),
+            ..Self::new()
+        }
+    }
+
+    /// Looks up the quantization table used by the frame component `component_id`.
+    ///
+    /// DQT segments may also follow the frame header, so the table selected in the frame header
+    /// can only be resolved once a scan needs it.
+    fn quantization_table(&self, component_id: u8) -> Result<&QuantizationTable> {
+        let component_header = self
+            .frame_component_header(component_id)
+            .ok_or(Error::Parse("Scan component not in frame"))?;
+        self.quantization_tables[component_header.quantization_table_selector as usize]
+            .as_ref()
+            .ok_or(Error::Parse("Missing quantization table"))
+    }
+
+    /// Converts the decoded component planes to an image, which is grayscale for a single
+    /// component, RGB for three components and CMYK for four components. Subsampled planes are
+    /// scaled to the image resolution by `upsampler` first.
+    fn to_decoded_image(&self, upsampler: &dyn Upsampler) -> Result<DecodedImage> {
+        let frame_header = self
+            .frame_header
+            .as_ref()
+            .ok_or(Error::Parse("Missing frame header"))?;
+        let (pixels, format) = convert_lines(
+            frame_header,
+            &self.components,
+            self.adobe_transform,
+            upsampler,
+            0..frame_header.max_lines as usize,
+        )?;
+
+        Ok(DecodedImage {
+            width: frame_header.max_samples_per_line,
+            height: frame_header.max_lines,
+            pixels,
+            format,
+            scan_layout: self.scan_layout(),
+        })
+    }
+
+    /// Allocates the component planes that aren't allocated yet, reusing the buffers in
+    /// `spare_planes`. Each plane holds at most `window` MCU lines, or all of them.
+    fn allocate_planes(&mut self, window: Option<usize>, spare_planes: &mut Vec<Vec<u8>>) {
+        for component in &mut self.components {
+            if !component.is_allocated() {
+                component.allocate(spare_planes.pop().unwrap_or_default(), window);
+            }
+        }
+    }
+
+    /// Hands the lines of the decoded image that weren't handed out yet to `rows`.
+    fn emit_rows(
+        &mut self,
+        rows: &mut RowSink,
+        upsampler: &dyn Upsampler,
+        spare_planes: &mut Vec<Vec<u8>>,
+    ) -> Result<()> {
+        // Without any scan the planes of a streamed image were never allocated
+        self.allocate_planes(None, spare_planes);
+        let frame_header = self
+            .frame_header
+            .as_ref()
+            .ok_or(Error::Parse("Missing frame header"))?;
+        rows.emit(
+            frame_header,
+            &self.components,
+            self.adobe_transform,
+            upsampler,
+            frame_header.max_lines as usize,
+        )
+    }
+
+    fn scan_layout(&self) -> ScanLayout {
+        let progressive = self.frame_header.as_ref().is_some_and(|frame_header| {
+            matches!(
+                frame_header.encoding_process,
+                EncodingProcess::ProgressiveDctHc | EncodingProcess::ProgressiveDctAc
+            )
+        });
+        if progressive {
+            ScanLayout::Progressive
+        } else if self
+            .scans
+            .iter()
+            .any(|scan| scan.scan_header.components_count > 1)
+        {
+            ScanLayout::Interleaved
+        } else {
+            ScanLayout::NonInterleaved
+        }
+    }
+
+    fn frame_component_header(&self, component_id: u8) -> Option<&FrameComponentHeader> {
+        self.frame_header
+            .as_ref()?
+            .component_headers
+            .iter()
+            .flatten()
+            .find(|component_header| component_header.id == component_id)
+    }
+}
+
+#[allow(dead_code)]
 struct FrameHeader {
     encoding_process: EncodingProcess,
     precision: u8,
@@ -22,6 +170,38 @@ struct FrameHeader {
     component_headers: [Option<FrameComponentHeader>; 4],
 }
 
+impl FrameHeader {
+    /// Returns width and height of an MCU in pixels, determined by the largest sampling factors
+    /// (A.2.4).
+    fn mcu_size(&self) -> (u16, u16) {
+        let component_headers = self.component_headers.iter().flatten();
+        let max_horizontal_sampling_factor = component_headers
+            .clone()
+            .map(|component_header| component_header.horizontal_sampling_factor)
+            .max()
+            .unwrap_or(1);
+        let max_vertical_sampling_factor = component_headers
+            .map(|component_header| component_header.vertical_sampling_factor)
+            .max()
+            .unwrap_or(1);
+        (
+            8 * max_horizontal_sampling_factor as u16,
+            8 * max_vertical_sampling_factor as u16,
+        )
+    }
+
+    /// Returns the number of MCUs per line and the number of MCU lines of an interleaved scan.
+    /// MCUs at the right and bottom edges are padded if the image doesn't fill them.
+    fn mcu_count(&self) -> (usize, usize) {
+        let (mcu_width, mcu_height) = self.mcu_size();
+        (
+            (self.max_samples_per_line as usize).div_ceil(mcu_width as usize),
+            (self.max_lines as usize).div_ceil(mcu_height as usize),
+        )
+    }
+}
+
+#[allow(dead_code)]
 struct FrameComponentHeader {
     id: u8,
     horizontal_sampling_factor: u8,
@@ -38,19 +218,31 @@ enum EncodingProcess {
     ExtendedSequentialDctAc,
     ProgressiveDctAc,
     LosslessAc,
+    // Differential processes, which only appear in the frames of hierarchical mode (Annex J)
+    DifferentialSequentialDctHc,
+    DifferentialProgressiveDctHc,
+    DifferentialLosslessHc,
+    DifferentialSequentialDctAc,
+    DifferentialProgressiveDctAc,
+    DifferentialLosslessAc,
 }
 
-type QuantizationTable = Vec<u8>;
+/// Quantization table in row-major order, like the coefficient blocks it applies to. Elements
+/// are widened to 16 bits, which is the larger of the two precisions DQT segments can use.
+pub type QuantizationTable = Vec<u16>;
 
 struct Scan {
     scan_header: ScanHeader,
-    // components: Vec<Component>,
-    // mcus: Vec<Mcu>,
 }
 
+#[allow(dead_code)]
 struct ScanHeader {
     components_count: u8,
     component_headers: [Option<ScanComponentHeader>; 4],
+    start_of_spectral_selection: u8,
+    end_of_spectral_selection: u8,
+    successive_approximation_high: u8,
+    successive_approximation_low: u8,
 }
 
 struct ScanComponentHeader {
@@ -61,128 +253,1017 @@ struct ScanComponentHeader {
 
 pub type HuffmanTable = [Vec<u8>; 16];
 
-// struct Component {}
+/// Sample plane of a frame component.
+///
+/// The plane covers every block of the padded MCUs, so blocks at the right and bottom edges can
+/// be stored without bounds checks. Only the top left `width` x `height` samples belong to the
+/// image.
+struct Component {
+    horizontal_sampling_factor: u8,
+    vertical_sampling_factor: u8,
+    // Size of the component in samples (A.1.1)
+    width: usize,
+    height: usize,
+    // Distance between the starts of two lines in `samples`
+    stride: usize,
+    // Number of MCU lines of the frame
+    mcu_lines: usize,
+    // Samples of the MCU lines starting at `first_mcu_line`. A plane decoded while streaming only
+    // holds a window of MCU lines, any other plane holds all of them. Empty until allocated.
+    samples: Vec<u8>,
+    first_mcu_line: usize,
+    // DC coefficient of the previous block, the DC coefficient of a block is coded as difference
+    // to it
+    dc_predictor: i32,
+}
+
+impl Component {
+    /// Sets up the plane of a frame component, unless the whole plane would hold more than
+    /// `max_samples` samples. The plane is allocated by `allocate`.
+    fn new(
+        component_header: &FrameComponentHeader,
+        frame_header: &FrameHeader,
+        max_samples: usize,
+    ) -> Result<Self> {
+        let (mcu_width, mcu_height) = frame_header.mcu_size();
+        let (mcus_per_line, mcu_lines) = frame_header.mcu_count();
+        let horizontal_sampling_factor = component_header.horizontal_sampling_factor as usize;
+        let vertical_sampling_factor = component_header.vertical_sampling_factor as usize;
+
+        // The MCU size is 8 times the largest sampling factors
+        let width = (frame_header.max_samples_per_line as usize * horizontal_sampling_factor * 8)
+            .div_ceil(mcu_width as usize);
+        let height = (frame_header.max_lines as usize * vertical_sampling_factor * 8)
+            .div_ceil(mcu_height as usize);
+        let stride = mcus_per_line * horizontal_sampling_factor * 8;
+        let lines = mcu_lines * vertical_sampling_factor * 8;
+        if stride * lines > max_samples {
+            return Err(Error::LimitExceeded("Component plane too large"));
+        }
+
+        Ok(Self {
+            horizontal_sampling_factor: component_header.horizontal_sampling_factor,
+            vertical_sampling_factor: component_header.vertical_sampling_factor,
+            width,
+            height,
+            stride,
+            mcu_lines,
+            samples: Vec::new(),
+            first_mcu_line: 0,
+            dc_predictor: 0,
+        })
+    }
+
+    /// Allocates the plane in `samples`, holding at most `window` MCU lines or all of them. The
+    /// previous content of `samples` is discarded.
+    fn allocate(&mut self, mut samples: Vec<u8>, window: Option<usize>) {
+        let mcu_lines = window.map_or(self.mcu_lines, |window| window.min(self.mcu_lines));
+        samples.clear();
+        samples.resize(mcu_lines * self.mcu_line_samples(), 0);
+        self.samples = samples;
+        self.first_mcu_line = 0;
+    }
+
+    fn is_allocated(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    fn mcu_line_samples(&self) -> usize {
+        self.stride * self.vertical_sampling_factor as usize * 8
+    }
+
+    /// Moves the window of MCU lines held by the plane on, so it ends with `mcu_line`. The lines
+    /// dropped from its start have to be converted already.
+    fn advance_window(&mut self, mcu_line: usize) {
+        let mcu_line_samples = self.mcu_line_samples();
+        let held_mcu_lines = self.samples.len() / mcu_line_samples;
+        let end = self.first_mcu_line + held_mcu_lines;
+        if mcu_line < end {
+            return;
+        }
+
+        let dropped = (mcu_line + 1 - end).min(held_mcu_lines);
+        self.samples.copy_within(dropped * mcu_line_samples.., 0);
+        let kept = self.samples.len() - dropped * mcu_line_samples;
+        // Blocks of skipped restart intervals keep the samples of a fresh plane
+        self.samples[kept..].fill(0);
+        self.first_mcu_line = mcu_line + 1 - held_mcu_lines;
+    }
+
+    /// Scales the samples of the MCU lines `mcu_lines` to the padded MCUs of a component with the
+    /// largest sampling factors `max_horizontal_sampling_factor` and
+    /// `max_vertical_sampling_factor`.
+    fn upsample(
+        &self,
+        max_horizontal_sampling_factor: usize,
+        max_vertical_sampling_factor: usize,
+        mcu_lines: Range<usize>,
+        upsampler: &dyn Upsampler,
+    ) -> Cow<'_, [u8]> {
+        let horizontal_sampling_factor = self.horizontal_sampling_factor as usize;
+        let vertical_sampling_factor = self.vertical_sampling_factor as usize;
+        let mcu_line_samples = self.mcu_line_samples();
+        let mcu_lines = mcu_lines.start - self.first_mcu_line..mcu_lines.end - self.first_mcu_line;
+        let samples =
+            &self.samples[mcu_lines.start * mcu_line_samples..mcu_lines.end * mcu_line_samples];
+        if horizontal_sampling_factor == max_horizontal_sampling_factor
+            && vertical_sampling_factor == max_vertical_sampling_factor
+        {
+            return Cow::Borrowed(samples);
+        }
+
+        let lines = samples.len() / self.stride;
+        Cow::Owned(upsampler.upsample(
+            samples,
+            self.stride,
+            lines,
+            self.stride * max_horizontal_sampling_factor / horizontal_sampling_factor,
+            lines * max_vertical_sampling_factor / vertical_sampling_factor,
+        ))
+    }
+
+    /// Stores the samples of a row-major block at block column `x` and block line `y`. Blocks
+    /// outside the MCU lines held by the plane are dropped.
+    fn store_block(&mut self, x: usize, y: usize, block: &[u8; 64]) {
+        let vertical_sampling_factor = self.vertical_sampling_factor as usize;
+        let Some(y) = y.checked_sub(self.first_mcu_line * vertical_sampling_factor) else {
+            return;
+        };
+        if (y + 1) * 8 * self.stride > self.samples.len() {
+            return;
+        }
+        for (line, samples) in block.chunks_exact(8).enumerate() {
+            let start = (y * 8 + line) * self.stride + x * 8;
+            self.samples[start..start + 8].copy_from_slice(samples);
+        }
+    }
+}
+
+/// Irregularities in a file that don't prevent decoding but may point to corruption.
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// A DHT segment defined the same Huffman table more than once, the last definition wins.
+    DuplicateHuffmanTable { class: u8, id: u8 },
+}
+
+/// Tables used by one component of a scan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanComponentTables {
+    pub component_id: u8,
+    pub dc_table: u8,
+    pub ac_table: u8,
+    /// Selected by the frame header rather than the scan header.
+    pub quantization_table: u8,
+}
+
+/// Summary of a parsed scan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanInfo {
+    pub components: Vec<ScanComponentTables>,
+}
+
+/// Text of a COM segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,
+    /// Encoding the text was decoded from.
+    pub encoding: CommentEncoding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommentEncoding {
+    Utf8,
+    /// Used as fallback for comments which aren't valid UTF-8.
+    Latin1,
+}
+
+/// Number of bytes at the end of a segment that are searched for the next marker, if the
+/// segment length points past it.
+const MAX_SEGMENT_OVERLAP: usize = 8;
+
+/// Number of MCU lines the planes of a streamed scan hold: the line being decoded, the line
+/// being converted and the line above it, which upsampling the converted line needs.
+const STREAMING_WINDOW: usize = 3;
+
+const JFXX_IDENTIFIER: &[u8] = b"JFXX\0";
+const PHOTOSHOP_IDENTIFIER: &[u8] = b"Photoshop 3.0\0";
+
+/// Progress of the decoder through the input.
+enum State {
+    Unread,
+    // `decode` succeeded and the image is kept
+    Decoded,
+    // The input was read by a call that failed or doesn't keep the image
+    Consumed,
+}
+
+/// Hands the pixel lines of an image to a callback as soon as they are decoded.
+struct RowSink<'a> {
+    callback: &'a mut dyn FnMut(u16, &[u8]),
+    // Number of lines handed out so far
+    lines: usize,
+}
+
+impl RowSink<'_> {
+    /// Converts the lines that weren't handed out yet up to line `end` and hands them out.
+    fn emit(
+        &mut self,
+        frame_header: &FrameHeader,
+        components: &[Component],
+        adobe_transform: Option<AdobeTransform>,
+        upsampler: &dyn Upsampler,
+        end: usize,
+    ) -> Result<()> {
+        let end = end.min(frame_header.max_lines as usize);
+        if end <= self.lines {
+            return Ok(());
+        }
 
-// struct Mcu {}
+        let (pixels, _) = convert_lines(
+            frame_header,
+            components,
+            adobe_transform,
+            upsampler,
+            self.lines..end,
+        )?;
+        let line_size = pixels.len() / (end - self.lines);
+        for (line, pixels) in (self.lines..end).zip(pixels.chunks_exact(line_size)) {
+            (self.callback)(line as u16, pixels);
+        }
+        self.lines = end;
+        Ok(())
+    }
+}
 
 pub struct Decoder<R: Read> {
-    reader: R,
+    reader: PushbackReader<R>,
+    state: State,
+    image: Image,
+    structure: JpegStructure,
+    upsampler: Box<dyn Upsampler>,
+    strict: bool,
+    nonblocking: bool,
+    max_plane_samples: usize,
+    comments: Vec<Comment>,
+    jfif: Option<JfifHeader>,
+    photoshop_irb: Option<Vec<u8>>,
+    warnings: Vec<Warning>,
+    // Sample buffers of the planes of the previous image, reused for the planes of the next one
+    spare_planes: Vec<Vec<u8>>,
 }
 
 impl<R: Read> Decoder<R> {
     pub fn new(reader: R) -> Self {
-        return Self { reader };
+        Self {
+            reader: PushbackReader::new(reader),
+            state: State::Unread,
+            image: Image::new(),
+            structure: JpegStructure::new(),
+            upsampler: Box::new(NearestUpsampler),
+            strict: false,
+            nonblocking: false,
+            max_plane_samples: usize::MAX,
+            comments: Vec::new(),
+            jfif: None,
+            photoshop_irb: None,
+            warnings: Vec::new(),
+            spare_planes: Vec::new(),
+        }
     }
 
-    pub fn decode(&mut self) -> Result<Vec<u8>> {
-        self.parse()?;
-        Ok(Vec::new())
+    /// Prepares the decoder for the next image read from `reader`.
+    ///
+    /// All state parsed from the previous image is dropped, while settings and the capacity of
+    /// internal buffers are kept.
+    pub fn reset(&mut self, reader: R) {
+        self.reader.reset(reader);
+        self.state = State::Unread;
+        self.clear_parsed();
     }
 
-    fn parse(&mut self) -> Result<()> {
-        let mut image = Image {
-            frame_header: None,
-            scans: Vec::new(),
-            quantization_tables: [None, None, None, None],
-            dc_huffman_tables: [None, None, None, None],
-            ac_huffman_tables: [None, None, None, None],
+    fn clear_parsed(&mut self) {
+        let image = std::mem::replace(&mut self.image, Image::new());
+        self.spare_planes.extend(
+            image
+                .components
+                .into_iter()
+                .map(|component| component.samples),
+        );
+        self.structure.clear();
+        self.comments.clear();
+        self.jfif = None;
+        self.photoshop_irb = None;
+        self.warnings.clear();
+    }
+
+    /// Enables strict decoding, which rejects files with inconsistent segment lengths or a
+    /// missing EOI marker instead of trying to recover from them. Decoding is lenient by default.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Declares that the reader may return `io::ErrorKind::WouldBlock`. The input consumed by a
+    /// call is then kept until the call returns, so it can start over once more data has
+    /// arrived, which takes as much memory as the compressed image. Without this, the input read
+    /// before the reader blocked is lost and the decoder has to be reset.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Limits the number of samples of each component plane, which is checked before the plane
+    /// is allocated. Planes are padded to whole MCUs. There is no limit by default.
+    pub fn set_max_plane_samples(&mut self, max_plane_samples: usize) {
+        self.max_plane_samples = max_plane_samples;
+    }
+
+    /// Returns the comments of all COM segments parsed so far.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    /// Replaces the upsampler used to scale subsampled components to the image resolution.
+    /// Defaults to `NearestUpsampler`.
+    pub fn set_upsampler(&mut self, upsampler: Box<dyn Upsampler>) {
+        self.upsampler = upsampler;
+    }
+
+    /// Returns the header of the JFIF APP0 segment, or `None` if the file doesn't have one.
+    pub fn jfif(&self) -> Option<&JfifHeader> {
+        self.jfif.as_ref()
+    }
+
+    /// Returns the Photoshop image resource blocks of the APP13 segments, which carry the IPTC
+    /// metadata. Resources split over several segments are concatenated.
+    pub fn photoshop_irb(&self) -> Option<&[u8]> {
+        self.photoshop_irb.as_deref()
+    }
+
+    /// Returns the outline of the segments parsed so far.
+    pub fn structure(&self) -> &JpegStructure {
+        &self.structure
+    }
+
+    /// Returns the warnings collected while parsing.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Returns the tables selected by each component of the scans parsed so far.
+    pub fn scans(&self) -> Vec<ScanInfo> {
+        self.image
+            .scans
+            .iter()
+            .map(|scan| ScanInfo {
+                components: scan
+                    .scan_header
+                    .component_headers
+                    .iter()
+                    .flatten()
+                    .map(|scan_component_header| ScanComponentTables {
+                        component_id: scan_component_header.scan_component_selector,
+                        dc_table: scan_component_header.dc_entropy_coding_table_selector,
+                        ac_table: scan_component_header.ac_entropy_coding_table_selector,
+                        // Scan components are checked to be part of the frame when parsed
+                        quantization_table: self
+                            .image
+                            .frame_component_header(scan_component_header.scan_component_selector)
+                            .map_or(0, |frame_component_header| {
+                                frame_component_header.quantization_table_selector
+                            }),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Returns the embedded thumbnail JPEG from the EXIF or JFIF extension APP segments.
+    ///
+    /// Only the segments in front of the first scan are read, so this is cheap even for large
+    /// images. The decoder has to be reset before it can decode the main image, until then
+    /// `decode` returns `Error::AlreadyConsumed`.
+    pub fn extract_thumbnail(&mut self) -> Result<Option<Vec<u8>>> {
+        self.read_input(State::Consumed, Self::read_thumbnail)
+    }
+
+    fn read_thumbnail(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            match Marker::from_reader(&mut self.reader) {
+                Ok(Marker::StartOfImage) => {}
+                Ok(Marker::ApplicationSegment(n, size)) => {
+                    let data = self.read_segment(size)?;
+                    // A broken segment doesn't rule out a thumbnail in a later one
+                    if let Ok(Some(thumbnail)) = app_segment_thumbnail(n, &data) {
+                        return Ok(Some(thumbnail.to_vec()));
+                    }
+                }
+                Ok(Marker::Comment(size))
+                | Ok(Marker::DefineQuantizationTable(size))
+                | Ok(Marker::StartOfFrame(_, size))
+                | Ok(Marker::DefineHuffmanTable(size)) => {
+                    self.read_segment(size)?;
+                }
+                Ok(Marker::DefineRestartInterval(_)) | Ok(Marker::Restart(_)) => {}
+                Ok(Marker::StartOfScan(_)) | Ok(Marker::EndOfImage) => return Ok(None),
+                Err(Error::Unsupported(_)) => return Err(Error::Parse("Non allowed marker found")),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Returns every JPEG stream contained in the file: the main image, the thumbnails from the
+    /// EXIF and JFIF extension segments and the further images of a Multi-Picture (MPO) file.
+    ///
+    /// The remaining input is read completely. The decoder has to be reset before it can decode
+    /// another image, until then `decode` returns `Error::AlreadyConsumed`.
+    pub fn extract_all_images(&mut self) -> Result<Vec<Vec<u8>>> {
+        self.read_input(State::Consumed, Self::read_all_images)
+    }
+
+    fn read_all_images(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut data = Vec::new();
+        self.reader.read_to_end(&mut data)?;
+
+        let mut thumbnails = Vec::new();
+        let mut mp_images = Vec::new();
+        let mut reader = &data[..];
+        loop {
+            let marker = Marker::from_reader(&mut reader).map_err(|err| match err {
+                Error::Unsupported(_) => Error::Parse("Non allowed marker found"),
+                err => err,
+            })?;
+            let segment_start = data.len() - reader.len();
+            match marker {
+                Marker::StartOfImage => {}
+                Marker::ApplicationSegment(n, size) => {
+                    let segment = split_segment(&mut reader, size)?;
+                    // Broken metadata is common, it only loses the images it points to
+                    if let Ok(Some(thumbnail)) = app_segment_thumbnail(n, segment) {
+                        thumbnails.push(thumbnail.to_vec());
+                    }
+                    if n == 2 {
+                        if let Ok(Some(entries)) = mpf::entries(segment) {
+                            // Offsets are relative to the MP header behind the identifier
+                            let mp_header = segment_start + 4;
+                            mp_images.extend(
+                                entries
+                                    .iter()
+                                    .filter(|entry| entry.offset != 0)
+                                    .map(|entry| (mp_header + entry.offset, entry.size)),
+                            );
+                        }
+                    }
+                }
+                Marker::Comment(size)
+                | Marker::DefineQuantizationTable(size)
+                | Marker::StartOfFrame(_, size)
+                | Marker::DefineHuffmanTable(size)
+                | Marker::StartOfScan(size) => {
+                    split_segment(&mut reader, size)?;
+                }
+                // Restart markers are only found between the scan data
+                Marker::DefineRestartInterval(_) | Marker::Restart(_) => {}
+                Marker::EndOfImage => break,
+            }
+        }
+
+        let mut images = vec![data[..data.len() - reader.len()].to_vec()];
+        images.append(&mut thumbnails);
+        for (offset, size) in mp_images {
+            if let Some(image) = data.get(offset..offset + size) {
+                images.push(image.to_vec());
+            }
+        }
+
+        Ok(images)
+    }
+
+    /// Returns the number of bytes following the EOI marker of the decoded image, or of the last
+    /// image decoded by `decode_all_frames`, which point to padded or concatenated files.
+    ///
+    /// The remaining input is read completely. The decoder has to be reset before it can decode
+    /// another image. Before the input was decoded, `Error::NotDecoded` is returned.
+    pub fn trailing_bytes(&mut self) -> Result<u64> {
+        if let State::Unread = self.state {
+            return Err(Error::NotDecoded);
+        }
+        Ok(io::copy(&mut self.reader, &mut io::sink())?)
+    }
+
+    /// Returns the width and height of the image, or `None` if no frame header has been parsed
+    /// yet.
+    pub fn dimensions(&self) -> Option<(u16, u16)> {
+        let frame_header = self.image.frame_header.as_ref()?;
+        Some((frame_header.max_samples_per_line, frame_header.max_lines))
+    }
+
+    /// Checks whether a rectangle lies on MCU boundaries, which is required to crop or rotate an
+    /// image losslessly. Edges may also end at the border of the image, where the last MCU is
+    /// padded.
+    ///
+    /// Returns `None` if no frame header has been parsed yet.
+    pub fn is_mcu_aligned(&self, x: u16, y: u16, width: u16, height: u16) -> Option<bool> {
+        let frame_header = self.image.frame_header.as_ref()?;
+        let (mcu_width, mcu_height) = frame_header.mcu_size();
+
+        let right = x as u32 + width as u32;
+        let bottom = y as u32 + height as u32;
+        let image_width = frame_header.max_samples_per_line as u32;
+        let image_height = frame_header.max_lines as u32;
+
+        Some(
+            right <= image_width
+                && bottom <= image_height
+                && x.is_multiple_of(mcu_width)
+                && y.is_multiple_of(mcu_height)
+                && (right.is_multiple_of(mcu_width as u32) || right == image_width)
+                && (bottom.is_multiple_of(mcu_height as u32) || bottom == image_height),
+        )
+    }
+
+    /// Decodes the image to grayscale samples for a single component, RGB samples for three
+    /// components and CMYK samples for four components.
+    ///
+    /// Calling this again returns the same image without reading any further input, or
+    /// `Error::AlreadyConsumed` if the first call failed. If the reader would block,
+    /// `Error::WouldBlock` is returned and, with `set_nonblocking`, decoding starts over once this
+    /// is called again.
+    pub fn decode(&mut self) -> Result<DecodedImage> {
+        if let State::Unread = self.state {
+            self.read_input(State::Decoded, |decoder| decoder.parse(None))?;
+        }
+        match self.state {
+            State::Decoded => self.image.to_decoded_image(self.upsampler.as_ref()),
+            _ => Err(Error::AlreadyConsumed),
+        }
+    }
+
+    /// Decodes every image of a stream in abbreviated format, where tables are defined once and
+    /// then shared by the images that follow them, until the end of the input.
+    ///
+    /// Images without a frame, like the table-specification data that usually starts such a
+    /// stream, don't produce an output. The stream ends when no further SOI marker follows, the
+    /// bytes after the last image are counted by `trailing_bytes`. Like `decode`, this has to be
+    /// the first call reading the input, and the decoder has to be reset before it can decode
+    /// another stream.
+    pub fn decode_all_frames(&mut self) -> Result<Vec<DecodedImage>> {
+        self.read_input(State::Consumed, Self::parse_all_frames)
+    }
+
+    fn parse_all_frames(&mut self) -> Result<Vec<DecodedImage>> {
+        let mut images = Vec::new();
+        // Bytes without a further SOI marker are padding, which is left for `trailing_bytes`
+        while let Some(offset) = self.reader.find(&[0xff, 0xd8])? {
+            io::copy(&mut (&mut self.reader).take(offset as u64), &mut io::sink())?;
+
+            let mut image = self.image.take_tables();
+            let result = self.parse_markers(&mut image, None);
+            self.image = image;
+            result?;
+
+            if self.image.frame_header.is_some() {
+                images.push(self.image.to_decoded_image(self.upsampler.as_ref())?);
+            }
+        }
+        Ok(images)
+    }
+
+    /// Decodes the image like `decode`, but hands each line of pixels to `callback` together with
+    /// its index instead of returning the whole image.
+    ///
+    /// If a single scan holds all components, as in most baseline images, the lines are handed
+    /// out while the scan is decoded, one MCU line behind the entropy decoder, so upsampling can
+    /// take the next line into account. The component planes only hold a few MCU lines then.
+    /// Otherwise the whole planes are decoded and the lines are handed out after the last scan.
+    ///
+    /// The image isn't kept, so the decoder has to be reset before it can decode again. If
+    /// `decode` succeeded before, the lines of its image are handed out instead. If the reader
+    /// would block, `Error::WouldBlock` is returned and, with `set_nonblocking`, the next call
+    /// starts over from the first line.
+    pub fn decode_rows(&mut self, mut callback: impl FnMut(u16, &[u8])) -> Result<()> {
+        let mut rows = RowSink {
+            callback: &mut callback,
+            lines: 0,
         };
+        match self.state {
+            State::Unread => self.read_input(State::Consumed, |decoder| {
+                decoder.parse(Some(&mut rows))?;
+                decoder.image.emit_rows(
+                    &mut rows,
+                    decoder.upsampler.as_ref(),
+                    &mut decoder.spare_planes,
+                )
+            }),
+            State::Decoded => {
+                self.image
+                    .emit_rows(&mut rows, self.upsampler.as_ref(), &mut self.spare_planes)
+            }
+            State::Consumed => Err(Error::AlreadyConsumed),
+        }
+    }
+
+    /// Runs `read` on the unread input and moves on to `done` if it succeeds.
+    ///
+    /// If the reader would block and was declared non-blocking, the bytes consumed so far are
+    /// given back and everything parsed from them is dropped, so the input can be read again once
+    /// more data has arrived. If `read` fails otherwise, the input counts as consumed.
+    fn read_input<T>(
+        &mut self,
+        done: State,
+        read: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        if !matches!(self.state, State::Unread) {
+            return Err(Error::AlreadyConsumed);
+        }
 
+        // Only a non-blocking reader needs the consumed bytes to start over
+        if self.nonblocking {
+            self.reader.record();
+        }
+        let result = read(self);
+        match result {
+            Err(Error::WouldBlock) if self.nonblocking => {
+                self.reader.rewind();
+                self.clear_parsed();
+            }
+            Err(_) => self.state = State::Consumed,
+            Ok(_) => self.state = done,
+        }
+        self.reader.stop_recording();
+        result
+    }
+
+    fn parse(&mut self, rows: Option<&mut RowSink>) -> Result<()> {
+        // Keep everything parsed so far, even if parsing fails later on
+        let mut image = Image::new();
+        let result = self.parse_markers(&mut image, rows);
+        self.image = image;
+        result
+    }
+
+    fn parse_markers(&mut self, image: &mut Image, mut rows: Option<&mut RowSink>) -> Result<()> {
         loop {
             let marker = Marker::from_reader(&mut self.reader);
+            if let Ok(marker) = &marker {
+                marker.check_size().context("reading segment length")?;
+            }
             match marker {
-                Ok(Marker::StartOfImage) => println!("Marker: Start of Image"),
+                Ok(Marker::StartOfImage) => self.structure.push(0, "Start of Image"),
                 Ok(Marker::ApplicationSegment(n, size)) => {
-                    println!("Marker: Application Default Header({}) - {}", n, size);
-                    skip_bytes(&mut self.reader, size - 2)?;
+                    self.structure
+                        .push(0, format!("Application Default Header({}) - {}", n, size));
+                    let data = self
+                        .read_segment(size)
+                        .context("reading application segment")?;
+                    match n {
+                        0 => {
+                            if let Some(extension) = data.strip_prefix(JFXX_IDENTIFIER) {
+                                self.parse_jfif_extension(extension)
+                                    .context("reading application segment")?;
+                            } else if let Ok(Some(header)) = jfif::header(&data) {
+                                self.structure.push(
+                                    1,
+                                    format!(
+                                        "JFIF {}.{:02}, density {}x{} {:?}",
+                                        header.version_major,
+                                        header.version_minor,
+                                        header.x_density,
+                                        header.y_density,
+                                        header.density_units
+                                    ),
+                                );
+                                self.jfif = Some(header);
+                            }
+                        }
+                        1 => {
+                            if let Ok(Some(thumbnail)) = exif::thumbnail(&data) {
+                                self.structure
+                                    .push(1, format!("EXIF thumbnail: {} bytes", thumbnail.len()));
+                            }
+                        }
+                        13 => {
+                            if let Some(irb) = data.strip_prefix(PHOTOSHOP_IDENTIFIER) {
+                                self.structure.push(
+                                    1,
+                                    format!("Photoshop image resources: {} bytes", irb.len()),
+                                );
+                                self.photoshop_irb
+                                    .get_or_insert_with(Vec::new)
+                                    .extend_from_slice(irb);
+                            }
+                        }
+                        14 => {
+                            if let Ok(Some(transform)) = adobe::transform(&data) {
+                                self.structure
+                                    .push(1, format!("Adobe color transform: {:?}", transform));
+                                image.adobe_transform = Some(transform);
+                            }
+                        }
+                        _ => {}
+                    }
                 }
                 Ok(Marker::Comment(size)) => {
-                    println!("Marker: Comment - {}", size);
-                    self.parse_comment(size)?;
+                    self.structure.push(0, format!("Comment - {}", size));
+                    let data = self.read_segment(size).context("reading comment")?;
+                    self.parse_comment(&data).context("reading comment")?;
                 }
                 Ok(Marker::DefineQuantizationTable(size)) => {
-                    println!("Marker: Define Quantization Table - {}", size);
-                    let tables = self.parse_quantization_table(size)?;
+                    self.structure
+                        .push(0, format!("Define Quantization Table - {}", size));
+                    let data = self
+                        .read_segment(size)
+                        .context("reading quantization table")?;
+                    let tables = self
+                        .parse_quantization_table(&data)
+                        .context("reading quantization table")?;
                     for table in tables {
                         image.quantization_tables[table.0 as usize] = Some(table.1);
                     }
                 }
                 Ok(Marker::StartOfFrame(n, size)) => {
-                    println!("Marker: Start of Frame({}) - {}", n, size);
-                    image.frame_header = Some(self.parse_frame_header(n, size)?);
+                    self.structure
+                        .push(0, format!("Start of Frame({}) - {}", n, size));
+                    let data = self.read_segment(size).context("reading frame header")?;
+                    let frame_header = self
+                        .parse_frame_header(n, &data)
+                        .context("reading frame header")?;
+                    let planes = std::mem::take(&mut image.components);
+                    self.spare_planes
+                        .extend(planes.into_iter().map(|component| component.samples));
+                    image.components = frame_header
+                        .component_headers
+                        .iter()
+                        .flatten()
+                        .map(|component_header| {
+                            Component::new(component_header, &frame_header, self.max_plane_samples)
+                        })
+                        .collect::<Result<_>>()
+                        .context("reading frame header")?;
+                    image.frame_header = Some(frame_header);
+                    // A streamed image only needs a window of the planes if a single scan holds
+                    // all components, which is known once the first scan header is read
+                    if rows.is_none() {
+                        image.allocate_planes(None, &mut self.spare_planes);
+                    }
                 }
                 Ok(Marker::DefineHuffmanTable(size)) => {
-                    println!("Marker: Define Huffman Table - {}", size);
-                    let table_infos = self.parse_huffman_table(size)?;
+                    self.structure
+                        .push(0, format!("Define Huffman Table - {}", size));
+                    let data = self.read_segment(size).context("reading Huffman table")?;
+                    let table_infos = self
+                        .parse_huffman_table(&data)
+                        .context("reading Huffman table")?;
                     for table_info in table_infos {
-                        let tree = HuffmanTree::new(&table_info.2);
-                        tree.print_codes();
+                        let tree =
+                            HuffmanTree::new(&table_info.2).context("reading Huffman table")?;
+                        for (code, value) in tree.codes() {
+                            self.structure
+                                .push(2, format!("Code: {} Value: {}", code, value));
+                        }
+                        let fast_table = tree.build_fast_table();
                         if table_info.0 == 0 {
-                            image.dc_huffman_tables[table_info.1 as usize] = Some(tree);
+                            image.dc_huffman_tables[table_info.1 as usize] = Some(fast_table);
                         } else {
-                            image.ac_huffman_tables[table_info.1 as usize] = Some(tree);
+                            image.ac_huffman_tables[table_info.1 as usize] = Some(fast_table);
                         }
                     }
                 }
                 Ok(Marker::StartOfScan(size)) => {
-                    println!("Marker: Start of Scan - {}", size);
-                    let scan_header =
-                        self.parse_scan_header(size, &image.frame_header.as_ref().unwrap())?;
+                    self.structure.push(0, format!("Start of Scan - {}", size));
+                    let data = self
+                        .read_segment_data(size)
+                        .context("reading scan header")?;
+                    let frame_header = image
+                        .frame_header
+                        .as_ref()
+                        .ok_or(Error::Parse("Scan before frame header"))
+                        .context("reading scan header")?;
+                    let scan_header = self
+                        .parse_scan_header(&data, frame_header)
+                        .context("reading scan header")?;
+                    for scan_component_header in scan_header.component_headers.iter().flatten() {
+                        image
+                            .quantization_table(scan_component_header.scan_component_selector)
+                            .context("reading scan header")?;
+                    }
+                    let window = (scan_header.components_count == frame_header.components_count)
+                        .then_some(STREAMING_WINDOW);
+                    image.allocate_planes(window, &mut self.spare_planes);
                     image.scans.push(Scan { scan_header });
 
-                    self.decode_scan();
+                    self.decode_scan(image, rows.as_deref_mut())
+                        .context("decoding scan")?;
                 }
+                Ok(Marker::DefineRestartInterval(restart_interval)) => {
+                    self.structure.push(0, "Define Restart Interval");
+                    self.structure
+                        .push(1, format!("Restart interval: {}", restart_interval));
+                    image.restart_interval = restart_interval;
+                }
+                // Restart markers are consumed by the scan decoder, stray ones are ignored
+                Ok(Marker::Restart(_)) => {}
                 Ok(Marker::EndOfImage) => {
-                    println!("Marker: End of Image");
+                    self.structure.push(0, "End of Image");
+                    break;
+                }
+                // Some writers truncate the file right after the last scan. The image is complete,
+                // so lenient decoding accepts it.
+                Err(Error::Io(err))
+                    if err.kind() == io::ErrorKind::UnexpectedEof && !image.scans.is_empty() =>
+                {
+                    if self.strict {
+                        return Err(Error::Parse("Missing End of Image marker"));
+                    }
+                    self.structure.push(0, "Missing End of Image");
                     break;
                 }
-                Err(_) => return Err(Error::Parse("Non allowed marker found")),
+                Err(Error::Unsupported(_)) => return Err(Error::Parse("Non allowed marker found")),
+                Err(err) => return Err(err),
             }
         }
         Ok(())
     }
 
-    fn parse_comment(&mut self, size: u16) -> Result<()> {
-        let mut comment_raw = vec![0; (size as usize) - 2];
-        self.reader.read_exact(&mut comment_raw)?;
+    /// Reads the payload of a marker segment whose length field is `size`.
+    ///
+    /// Every segment except SOS has to be followed by the next marker. If it isn't and one of the
+    /// last bytes of the segment starts a plausible segment, the declared length points into the
+    /// next marker. Strict decoding rejects this, lenient decoding truncates the segment at that
+    /// marker and continues from there. Other bytes following the segment are left to the
+    /// marker reader, which skips them.
+    fn read_segment(&mut self, size: u16) -> Result<Vec<u8>> {
+        let mut data = self.read_segment_data(size)?;
 
-        if let Ok(comment) = String::from_utf8(comment_raw.clone()) {
-            println!("\t{}", comment);
-        } else {
-            println!("\t{:?}", comment_raw);
+        let next = match self.reader.peek_u8()? {
+            Some(next) => next,
+            None => return Ok(data),
+        };
+        if next == 0xff {
+            return Ok(data);
+        }
+
+        // Only the last bytes are searched, a marker further in is more likely part of the
+        // payload, like the EOI of an embedded thumbnail followed by padding
+        let search_start = data.len().saturating_sub(MAX_SEGMENT_OVERLAP);
+        let mut tail = data[search_start..].to_vec();
+        tail.extend(self.reader.peek(5)?);
+        let is_marker_start = |i: usize| {
+            let mut rest = &tail[i - search_start..];
+            // Only a segment with a valid length is plausible. SOI, EOI and RSTn are more likely
+            // part of the payload.
+            read_u8(&mut rest).is_ok_and(|byte| byte == 0xff)
+                && read_u8(&mut rest)
+                    .and_then(|code| Marker::from_code(code, &mut rest))
+                    .is_ok_and(|marker| {
+                        !matches!(
+                            marker,
+                            Marker::StartOfImage | Marker::EndOfImage | Marker::Restart(_)
+                        ) && marker.check_size().is_ok()
+                    })
+        };
+        let Some(mut marker_start) = (search_start..data.len())
+            .rev()
+            .find(|i| is_marker_start(*i))
+        else {
+            // Stray bytes between segments are skipped by the marker reader
+            return Ok(data);
+        };
+
+        if self.strict {
+            return Err(Error::Parse("Segment length overlaps next marker"));
+        }
+
+        // Include fill bytes preceding the marker
+        while marker_start > 0 && data[marker_start - 1] == 0xff {
+            marker_start -= 1;
+        }
+        self.structure.push(
+            1,
+            format!(
+                "Segment length overlaps next marker by {} bytes",
+                data.len() - marker_start
+            ),
+        );
+        self.reader.unread(&data[marker_start..]);
+        data.truncate(marker_start);
+
+        Ok(data)
+    }
+
+    fn read_segment_data(&mut self, size: u16) -> Result<Vec<u8>> {
+        if size < 2 {
+            return Err(Error::Parse("Segment length too small"));
         }
 
+        let mut data = vec![0; (size as usize) - 2];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn parse_jfif_extension(&mut self, data: &[u8]) -> Result<()> {
+        // JFIF 1.02, JFIF extension APP0 marker segment
+        let mut reader = data;
+
+        let thumbnail_format = match read_u8(&mut reader)? {
+            0x10 => "JPEG",
+            0x11 => "1 byte per pixel palettized",
+            0x13 => "3 bytes per pixel RGB",
+            _ => return Err(Error::Parse("Invalid JFXX thumbnail format")),
+        };
+        self.structure
+            .push(1, format!("JFXX thumbnail format: {}", thumbnail_format));
+
         Ok(())
     }
 
-    fn parse_huffman_table(&mut self, size: u16) -> Result<Vec<(u8, u8, HuffmanTable)>> {
-        let mut bytes_read = 0;
+    fn parse_comment(&mut self, data: &[u8]) -> Result<()> {
+        // The specification doesn't define an encoding for comments. Most writers use UTF-8 or
+        // ASCII, older ones often Latin-1, which maps every byte to the same code point.
+        let comment = match String::from_utf8(data.to_vec()) {
+            Ok(text) => Comment {
+                text,
+                encoding: CommentEncoding::Utf8,
+            },
+            Err(_) => Comment {
+                text: data.iter().map(|byte| *byte as char).collect(),
+                encoding: CommentEncoding::Latin1,
+            },
+        };
 
-        let mut tables = Vec::new();
+        self.structure.push(1, comment.text.clone());
+        self.comments.push(comment);
 
-        while bytes_read < (size - 2) {
-            let table_info = read_u8(&mut self.reader)?;
-            bytes_read += 1;
+        Ok(())
+    }
+
+    fn parse_huffman_table(&mut self, data: &[u8]) -> Result<Vec<(u8, u8, HuffmanTable)>> {
+        let mut reader = data;
+
+        let mut tables: Vec<(u8, u8, HuffmanTable)> = Vec::new();
+
+        while !reader.is_empty() {
+            // Each table needs at least its class/destination byte and the 16 length counts
+            if reader.len() < 17 {
+                return Err(Error::Parse("Huffman table segment has trailing bytes"));
+            }
+
+            let table_info = read_u8(&mut reader)?;
             let huffman_table_class = (table_info & 0xf0) >> 4; // 0 == DC, 1 == AC
-            println!(
-                "\tHuffman table class: {}",
-                if huffman_table_class == 0 { "DC" } else { "AC" }
+            self.structure.push(
+                1,
+                format!(
+                    "Huffman table class: {}",
+                    if huffman_table_class == 0 { "DC" } else { "AC" }
+                ),
             );
             let huffman_table_destination_identifier = table_info & 0x0f;
-            println!(
-                "\tHuffman table destination identifier: {}",
-                huffman_table_destination_identifier
+            self.structure.push(
+                1,
+                format!(
+                    "Huffman table destination identifier: {}",
+                    huffman_table_destination_identifier
+                ),
             );
+            if huffman_table_class > 1 || huffman_table_destination_identifier > 3 {
+                return Err(Error::Parse("Invalid Huffman table class or destination"));
+            }
+
+            // Redefining a table is legal across segments, but within one segment the earlier
+            // definition can never be used
+            if tables.iter().any(|table| {
+                table.0 == huffman_table_class && table.1 == huffman_table_destination_identifier
+            }) {
+                self.structure.push(
+                    1,
+                    "Warning: Huffman table defined twice in the same segment",
+                );
+                self.warnings.push(Warning::DuplicateHuffmanTable {
+                    class: huffman_table_class,
+                    id: huffman_table_destination_identifier,
+                });
+            }
 
             let mut numbers_of_huffman_codes_of_length = [0; 16];
-            self.reader
-                .read_exact(&mut numbers_of_huffman_codes_of_length)?;
-            bytes_read += 16;
-            println!(
-                "\tHuffman code lengths: {:?}",
-                numbers_of_huffman_codes_of_length
+            reader.read_exact(&mut numbers_of_huffman_codes_of_length)?;
+            self.structure.push(
+                1,
+                format!(
+                    "Huffman code lengths: {:?}",
+                    numbers_of_huffman_codes_of_length
+                ),
             );
 
+            let values_count: usize = numbers_of_huffman_codes_of_length
+                .iter()
+                .map(|count| *count as usize)
+                .sum();
+            // Symbols are bytes, so there can't be more distinct ones
+            if values_count > 256 {
+                return Err(Error::Parse("Huffman table has more than 256 values"));
+            }
+            if values_count > reader.len() {
+                return Err(Error::Parse("Huffman table values exceed segment length"));
+            }
+
             let mut huffman_table: HuffmanTable = Default::default();
 
             for (i, numbers_of_huffman_codes) in
@@ -194,14 +1275,14 @@ impl<R: Read> Decoder<R> {
 
                 let mut huffman_values = Vec::new();
                 for _ in 0..*numbers_of_huffman_codes {
-                    let value = read_u8(&mut self.reader)?;
-                    bytes_read += 1;
+                    let value = read_u8(&mut reader)?;
                     huffman_values.push(value);
                 }
                 huffman_table[i] = huffman_values;
             }
 
-            println!("\tHuffman table: {:?}", huffman_table);
+            self.structure
+                .push(1, format!("Huffman table: {:?}", huffman_table));
 
             tables.push((
                 huffman_table_class,
@@ -213,31 +1294,54 @@ impl<R: Read> Decoder<R> {
         Ok(tables)
     }
 
-    fn parse_quantization_table(&mut self, size: u16) -> Result<Vec<(u8, QuantizationTable)>> {
-        let mut bytes_read = 0;
+    fn parse_quantization_table(&mut self, data: &[u8]) -> Result<Vec<(u8, QuantizationTable)>> {
+        let mut reader = data;
 
         let mut tables = Vec::new();
 
-        while bytes_read < size - 2 {
-            let quantization_table_info = read_u8(&mut self.reader)?;
-            bytes_read += 1;
+        while !reader.is_empty() {
+            let quantization_table_info = read_u8(&mut reader)?;
 
+            // 0 for 8-bit elements, 1 for 16-bit elements
             let quantization_table_element_precision = (quantization_table_info & 0xf0) >> 4;
-            assert!(quantization_table_element_precision == 0);
-            println!(
-                "\tQuantization table element precision: {}",
-                quantization_table_element_precision
+            if quantization_table_element_precision > 1 {
+                return Err(Error::Parse("Invalid quantization table element precision"));
+            }
+            self.structure.push(
+                1,
+                format!(
+                    "Quantization table element precision: {}",
+                    quantization_table_element_precision
+                ),
             );
             let quantization_table_destination_identifier = quantization_table_info & 0x0f;
-            println!(
-                "\tQuantization table destination identifer: {}",
-                quantization_table_destination_identifier
+            self.structure.push(
+                1,
+                format!(
+                    "Quantization table destination identifer: {}",
+                    quantization_table_destination_identifier
+                ),
             );
+            if quantization_table_destination_identifier > 3 {
+                return Err(Error::Parse("Invalid quantization table destination"));
+            }
 
+            // Elements are stored in zig-zag order. A redefinition replaces the whole table, so
+            // its precision may differ from the previous definition.
             let mut quantization_table = vec![0; 64];
-            self.reader.read_exact(&mut quantization_table)?;
-            println!("\tQuantization table: {:?}", quantization_table);
-            bytes_read += 64 * (quantization_table_element_precision as u16 + 1);
+            for i in ZIGZAG {
+                quantization_table[i] = if quantization_table_element_precision == 0 {
+                    read_u8(&mut reader)? as u16
+                } else {
+                    read_u16_be(&mut reader)?
+                };
+            }
+            // A zero step would discard its coefficient, which no valid encoder produces
+            if quantization_table.contains(&0) {
+                return Err(Error::Parse("Quantization table has zero element"));
+            }
+            self.structure
+                .push(1, format!("Quantization table: {:?}", quantization_table));
 
             tables.push((
                 quantization_table_destination_identifier,
@@ -248,33 +1352,74 @@ impl<R: Read> Decoder<R> {
         Ok(tables)
     }
 
-    fn parse_scan_header(&mut self, _size: u16, _frame_header: &FrameHeader) -> Result<ScanHeader> {
+    fn parse_scan_header(&mut self, data: &[u8], frame_header: &FrameHeader) -> Result<ScanHeader> {
         // B.2.3
+        let mut reader = data;
 
-        let components_count = read_u8(&mut self.reader)?;
-        println!("\tComponents count: {}", components_count);
-        assert!(0 < components_count && components_count < 4);
+        let components_count = read_u8(&mut reader)?;
+        self.structure
+            .push(1, format!("Components: {}", components_count));
+        if !(1..=4).contains(&components_count) {
+            return Err(Error::Parse("Invalid number of scan components"));
+        }
+        if components_count > frame_header.components_count {
+            return Err(Error::Parse("Scan has more components than frame"));
+        }
 
         let mut scan_header = ScanHeader {
             components_count,
             component_headers: [None, None, None, None],
+            start_of_spectral_selection: 0,
+            end_of_spectral_selection: 63,
+            successive_approximation_high: 0,
+            successive_approximation_low: 0,
         };
 
         for i in 0..components_count {
-            let scan_component_selector = read_u8(&mut self.reader)?;
-            println!("\t\tScan component selector: {}", scan_component_selector);
+            let scan_component_selector = read_u8(&mut reader)?;
+            self.structure.push(
+                2,
+                format!("Scan component selector: {}", scan_component_selector),
+            );
+            if !frame_header
+                .component_headers
+                .iter()
+                .flatten()
+                .any(|component_header| component_header.id == scan_component_selector)
+            {
+                return Err(Error::Parse("Scan component not in frame"));
+            }
+            if scan_header
+                .component_headers
+                .iter()
+                .flatten()
+                .any(|component_header| {
+                    component_header.scan_component_selector == scan_component_selector
+                })
+            {
+                return Err(Error::Parse("Duplicate scan component selector"));
+            }
 
-            let entropy_coding_table_selectors = read_u8(&mut self.reader)?;
+            let entropy_coding_table_selectors = read_u8(&mut reader)?;
             let dc_entropy_coding_table_selector = (entropy_coding_table_selectors & 0xf0) >> 4;
-            println!(
-                "\t\tDc entropy coding table selector: {}",
-                dc_entropy_coding_table_selector
+            self.structure.push(
+                2,
+                format!(
+                    "Dc entropy coding table selector: {}",
+                    dc_entropy_coding_table_selector
+                ),
             );
             let ac_entropy_coding_table_selector = entropy_coding_table_selectors & 0x0f;
-            println!(
-                "\t\tAc entropy coding table selector: {}",
-                ac_entropy_coding_table_selector
+            self.structure.push(
+                2,
+                format!(
+                    "Ac entropy coding table selector: {}",
+                    ac_entropy_coding_table_selector
+                ),
             );
+            if dc_entropy_coding_table_selector > 3 || ac_entropy_coding_table_selector > 3 {
+                return Err(Error::Parse("Invalid entropy coding table selector"));
+            }
 
             let scan_component_header = ScanComponentHeader {
                 scan_component_selector,
@@ -287,64 +1432,175 @@ impl<R: Read> Decoder<R> {
             scan_header.component_headers[i as usize] = Some(scan_component_header);
         }
 
-        // Skip 3 bytes that are meaningless for BaselineDCT
-        skip_bytes(&mut self.reader, 3)?;
+        // Spectral selection and successive approximation are only meaningful for progressive
+        // scans, sequential scans always cover all 64 coefficients at full precision
+        scan_header.start_of_spectral_selection = read_u8(&mut reader)?;
+        scan_header.end_of_spectral_selection = read_u8(&mut reader)?;
+        let successive_approximation = read_u8(&mut reader)?;
+        scan_header.successive_approximation_high = (successive_approximation & 0xf0) >> 4;
+        scan_header.successive_approximation_low = successive_approximation & 0x0f;
+        self.structure.push(
+            1,
+            format!(
+                "Spectral selection: {}..={}",
+                scan_header.start_of_spectral_selection, scan_header.end_of_spectral_selection
+            ),
+        );
+        self.structure.push(
+            1,
+            format!(
+                "Successive approximation: {} -> {}",
+                scan_header.successive_approximation_high, scan_header.successive_approximation_low
+            ),
+        );
+
+        if matches!(
+            frame_header.encoding_process,
+            EncodingProcess::ProgressiveDctHc | EncodingProcess::ProgressiveDctAc
+        ) {
+            // G.1.1.1.1
+            // DC scans may be interleaved, but a scan with AC coefficients must contain
+            // exactly one component and must not mix in the DC coefficient.
+            let start = scan_header.start_of_spectral_selection;
+            let end = scan_header.end_of_spectral_selection;
+            if start > end || end > 63 || (start == 0 && end != 0) {
+                return Err(Error::Parse("Invalid spectral selection"));
+            }
+            if start > 0 && components_count != 1 {
+                return Err(Error::Parse(
+                    "Progressive AC scan must contain one component",
+                ));
+            }
+        }
 
         Ok(scan_header)
     }
 
-    fn parse_frame_header(&mut self, n: u8, _size: u16) -> Result<FrameHeader> {
+    fn parse_frame_header(&mut self, n: u8, data: &[u8]) -> Result<FrameHeader> {
         // B.2.2
+        let mut reader = data;
 
         let encoding_process = match n {
             0 => {
-                println!("\tEncoding process: Baseline DCT");
+                self.structure.push(1, "Encoding process: Baseline DCT");
                 EncodingProcess::BaselineDct
             }
             1 => {
-                println!("\tEncoding process: Extended sequential DCT, Huffman coding");
+                self.structure.push(
+                    1,
+                    "Encoding process: Extended sequential DCT, Huffman coding",
+                );
                 EncodingProcess::ExtendedSequentialDctHc
             }
             2 => {
-                println!("\tEncoding process: Progressive DCT, Huffman coding");
+                self.structure
+                    .push(1, "Encoding process: Progressive DCT, Huffman coding");
                 EncodingProcess::ProgressiveDctHc
             }
             3 => {
-                println!("\tEncoding process: Lossless (sequential), Huffman coding");
+                self.structure
+                    .push(1, "Encoding process: Lossless (sequential), Huffman coding");
                 EncodingProcess::LosslessHc
             }
+            5 => {
+                self.structure.push(
+                    1,
+                    "Encoding process: Differential sequential DCT, Huffman coding",
+                );
+                EncodingProcess::DifferentialSequentialDctHc
+            }
+            6 => {
+                self.structure.push(
+                    1,
+                    "Encoding process: Differential progressive DCT, Huffman coding",
+                );
+                EncodingProcess::DifferentialProgressiveDctHc
+            }
+            7 => {
+                self.structure.push(
+                    1,
+                    "Encoding process: Differential lossless (sequential), Huffman coding",
+                );
+                EncodingProcess::DifferentialLosslessHc
+            }
             9 => {
-                println!("\tEncoding process: Extended sequential DCT, arithmetic coding");
-                EncodingProcess::ExtendedSequentialDctHc
+                self.structure.push(
+                    1,
+                    "Encoding process: Extended sequential DCT, arithmetic coding",
+                );
+                EncodingProcess::ExtendedSequentialDctAc
             }
             10 => {
-                println!("\tEncoding process: Progressive DCT, arithmetic coding");
+                self.structure
+                    .push(1, "Encoding process: Progressive DCT, arithmetic coding");
                 EncodingProcess::ProgressiveDctAc
             }
             11 => {
-                println!("\tEncoding process: Lossless (sequential), arithmetic coding");
+                self.structure.push(
+                    1,
+                    "Encoding process: Lossless (sequential), arithmetic coding",
+                );
                 EncodingProcess::LosslessAc
             }
+            13 => {
+                self.structure.push(
+                    1,
+                    "Encoding process: Differential sequential DCT, arithmetic coding",
+                );
+                EncodingProcess::DifferentialSequentialDctAc
+            }
+            14 => {
+                self.structure.push(
+                    1,
+                    "Encoding process: Differential progressive DCT, arithmetic coding",
+                );
+                EncodingProcess::DifferentialProgressiveDctAc
+            }
+            15 => {
+                self.structure.push(
+                    1,
+                    "Encoding process: Differential lossless (sequential), arithmetic coding",
+                );
+                EncodingProcess::DifferentialLosslessAc
+            }
             _ => {
-                println!("\tUnknown encoding process: {}", n);
+                self.structure
+                    .push(1, format!("Unknown encoding process: {}", n));
                 EncodingProcess::Unknown
             }
         };
 
-        let precision = read_u8(&mut self.reader)?;
-        println!("\tPrecision: {}", precision);
-        assert!(precision == 8);
+        let precision = read_u8(&mut reader)?;
+        self.structure.push(1, format!("Precision: {}", precision));
+        if precision != 8 {
+            return Err(Error::NotImplemented("Sample precisions other than 8 bits"));
+        }
 
-        let max_lines = read_u16_be(&mut self.reader)?;
-        println!("\tMax lines: {}", max_lines);
-        assert!(max_lines != 0);
+        let max_lines = read_u16_be(&mut reader)?;
+        self.structure.push(1, format!("Max lines: {}", max_lines));
+        // The number of lines may be defined by a DNL segment after the first scan instead
+        if max_lines == 0 {
+            return Err(Error::NotImplemented(
+                "Number of lines defined by DNL segment",
+            ));
+        }
 
-        let max_samples_per_line = read_u16_be(&mut self.reader)?;
-        println!("\tMax samples per line: {}", max_samples_per_line);
+        let max_samples_per_line = read_u16_be(&mut reader)?;
+        self.structure
+            .push(1, format!("Max samples per line: {}", max_samples_per_line));
+        if max_samples_per_line == 0 {
+            return Err(Error::Parse("Invalid number of samples per line"));
+        }
 
-        let components_count = read_u8(&mut self.reader)?;
-        println!("\tComponents count: {}", components_count);
-        assert!(components_count == 3);
+        let components_count = read_u8(&mut reader)?;
+        self.structure
+            .push(1, format!("Components: {}", components_count));
+        // Grayscale, YCbCr or RGB, CMYK or YCCK
+        if !matches!(components_count, 1 | 3 | 4) {
+            return Err(Error::NotImplemented(
+                "Frames with other than 1, 3 or 4 components",
+            ));
+        }
 
         let mut frame_header = FrameHeader {
             encoding_process,
@@ -356,23 +1612,37 @@ impl<R: Read> Decoder<R> {
         };
 
         for i in 0..components_count {
-            let id = read_u8(&mut self.reader)?;
-            println!("\t\tComponent id: {}", id);
+            let id = read_u8(&mut reader)?;
+            self.structure.push(2, format!("Component id: {}", id));
 
-            let sampling_factor = read_u8(&mut self.reader)?;
+            let sampling_factor = read_u8(&mut reader)?;
             let horizontal_sampling_factor = (sampling_factor & 0xf0) >> 4;
             let vertical_sampling_factor = sampling_factor & 0x0f;
-            println!(
-                "\t\tHorizontal sampling factor: {}",
-                horizontal_sampling_factor
+            self.structure.push(
+                2,
+                format!("Horizontal sampling factor: {}", horizontal_sampling_factor),
             );
-            println!("\t\tVertical sampling factor: {}", vertical_sampling_factor);
+            self.structure.push(
+                2,
+                format!("Vertical sampling factor: {}", vertical_sampling_factor),
+            );
+            if !(1..=4).contains(&horizontal_sampling_factor)
+                || !(1..=4).contains(&vertical_sampling_factor)
+            {
+                return Err(Error::Parse("Invalid sampling factor"));
+            }
 
-            let quantization_table_selector = read_u8(&mut self.reader)?;
-            println!(
-                "\t\tQuantization table selector: {}",
-                quantization_table_selector
+            let quantization_table_selector = read_u8(&mut reader)?;
+            self.structure.push(
+                2,
+                format!(
+                    "Quantization table selector: {}",
+                    quantization_table_selector
+                ),
             );
+            if quantization_table_selector > 3 {
+                return Err(Error::Parse("Invalid quantization table selector"));
+            }
 
             let component_header = FrameComponentHeader {
                 id,
@@ -386,16 +1656,1439 @@ impl<R: Read> Decoder<R> {
         Ok(frame_header)
     }
 
-    fn decode_scan(&self) {}
-}
-
-fn skip_bytes<R: Read>(reader: &mut R, size: u16) -> Result<()> {
-    let size = size as u64;
-    let to_skip = &mut reader.by_ref().take(size);
-    let copied = io::copy(to_skip, &mut io::sink())?;
-    if copied < size {
-        Err(Error::Io(io::ErrorKind::UnexpectedEof.into()))
-    } else {
-        Ok(())
+    fn decode_scan(&mut self, image: &mut Image, rows: Option<&mut RowSink>) -> Result<()> {
+        match image.frame_header.as_ref().unwrap().encoding_process {
+            EncodingProcess::BaselineDct | EncodingProcess::ExtendedSequentialDctHc => {
+                self.decode_sequential_scan(image, rows)
+            }
+            EncodingProcess::ProgressiveDctHc => {
+                Err(Error::NotImplemented("Progressive DCT decoding"))
+            }
+            EncodingProcess::LosslessHc | EncodingProcess::LosslessAc => {
+                Err(Error::NotImplemented("Lossless decoding"))
+            }
+            EncodingProcess::ExtendedSequentialDctAc | EncodingProcess::ProgressiveDctAc => {
+                Err(Error::NotImplemented("Arithmetic decoding"))
+            }
+            EncodingProcess::DifferentialSequentialDctHc
+            | EncodingProcess::DifferentialProgressiveDctHc
+            | EncodingProcess::DifferentialLosslessHc
+            | EncodingProcess::DifferentialSequentialDctAc
+            | EncodingProcess::DifferentialProgressiveDctAc
+            | EncodingProcess::DifferentialLosslessAc => {
+                Err(Error::NotImplemented("Hierarchical decoding"))
+            }
+            EncodingProcess::Unknown => Err(Error::Unsupported("Unknown encoding process")),
+        }
+    }
+
+    /// Decodes the entropy-coded data of a sequential DCT scan into the component planes.
+    ///
+    /// If the scan holds all components, the lines finished so far are handed to `rows` after
+    /// each line of MCUs.
+    fn decode_sequential_scan(
+        &mut self,
+        image: &mut Image,
+        mut rows: Option<&mut RowSink>,
+    ) -> Result<()> {
+        let Image {
+            frame_header,
+            scans,
+            quantization_tables,
+            dc_huffman_tables,
+            ac_huffman_tables,
+            components,
+            restart_interval,
+            adobe_transform,
+            ..
+        } = image;
+        let frame_header = frame_header.as_ref().unwrap();
+        let scan_header = &scans.last().unwrap().scan_header;
+
+        // Resolve the plane and the tables of every scan component up front
+        let mut scan_components = Vec::new();
+        for scan_component_header in scan_header.component_headers.iter().flatten() {
+            let (index, frame_component_header) = frame_header
+                .component_headers
+                .iter()
+                .flatten()
+                .enumerate()
+                .find(|(_, component_header)| {
+                    component_header.id == scan_component_header.scan_component_selector
+                })
+                .ok_or(Error::Parse("Scan component not in frame"))?;
+            let dc_table = dc_huffman_tables
+                [scan_component_header.dc_entropy_coding_table_selector as usize]
+                .as_ref()
+                .ok_or(Error::Parse("Missing DC Huffman table"))?;
+            let ac_table = ac_huffman_tables
+                [scan_component_header.ac_entropy_coding_table_selector as usize]
+                .as_ref()
+                .ok_or(Error::Parse("Missing AC Huffman table"))?;
+            let quantization_table = quantization_tables
+                [frame_component_header.quantization_table_selector as usize]
+                .as_ref()
+                .ok_or(Error::Parse("Missing quantization table"))?;
+
+            // Each scan starts with a prediction of zero (F.2.1.3.1)
+            components[index].dc_predictor = 0;
+            scan_components.push(BlockTables {
+                component: index,
+                dc_table,
+                ac_table,
+                quantization_table,
+            });
+        }
+
+        // Restart intervals count MCUs, which are single blocks in a non-interleaved scan
+        let interleaved = scan_components.len() > 1;
+        let (mcus_per_line, mcu_lines) = if interleaved {
+            frame_header.mcu_count()
+        } else {
+            // A non-interleaved scan holds the blocks of a single component line by line,
+            // without the padding of the MCUs (A.2.2)
+            let component = &components[scan_components[0].component];
+            (component.width.div_ceil(8), component.height.div_ceil(8))
+        };
+
+        // Number of image lines covered by a line of MCUs
+        let mcu_height = frame_header.mcu_size().1 as usize;
+        let line_height = if interleaved {
+            mcu_height
+        } else {
+            mcu_height / components[scan_components[0].component].vertical_sampling_factor as usize
+        };
+
+        let mut reader = BitReader::new(&mut self.reader);
+        let mut skip_interval = false;
+        let mut result = Ok(());
+        for mcu in 0..mcus_per_line * mcu_lines {
+            if *restart_interval != 0 && mcu != 0 && mcu % *restart_interval as usize == 0 {
+                // Every interval is followed by the next of the restart markers RST0 to RST7
+                // (B.2.1), after which decoding starts afresh (F.2.1.3.1)
+                let expected = ((mcu / *restart_interval as usize - 1) % 8) as u8;
+                result = reader.read_marker().and_then(|marker| {
+                    if marker == 0xd0 + expected {
+                        Ok(())
+                    } else {
+                        Err(Error::Parse("Unexpected restart marker"))
+                    }
+                });
+                if result.is_err() {
+                    break;
+                }
+                for tables in &scan_components {
+                    components[tables.component].dc_predictor = 0;
+                }
+                skip_interval = false;
+            }
+
+            let (x, y) = (mcu % mcus_per_line, mcu / mcus_per_line);
+            if x == 0 {
+                for tables in &scan_components {
+                    let component = &mut components[tables.component];
+                    let mcu_line = if interleaved {
+                        y
+                    } else {
+                        y / component.vertical_sampling_factor as usize
+                    };
+                    component.advance_window(mcu_line);
+                }
+            }
+            if !skip_interval {
+                result = decode_mcu(&mut reader, components, &scan_components, interleaved, x, y);
+            }
+            if result.is_err() {
+                // A restart marker inside an interval means data of the interval is missing.
+                // The rest of it can't be decoded, but the following intervals can.
+                let restart_marker = matches!(reader.marker(), Some(0xd0..=0xd7));
+                if *restart_interval == 0 || !restart_marker {
+                    break;
+                }
+                result = Ok(());
+                skip_interval = true;
+            }
+
+            if let Some(rows) = rows.as_deref_mut() {
+                if x == mcus_per_line - 1 && scan_components.len() == components.len() {
+                    // Keep the last line of MCUs back, upsampling the one before needs it
+                    let mcu_lines = (y + 1) * line_height / mcu_height;
+                    result = rows.emit(
+                        frame_header,
+                        components,
+                        *adobe_transform,
+                        self.upsampler.as_ref(),
+                        mcu_lines.saturating_sub(1) * mcu_height,
+                    );
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Give back a marker the entropy decoder ran into, so the marker loop can handle it
+        if let Some(marker) = reader.take_marker() {
+            self.reader.unread(&[0xff, marker]);
+        }
+        result
+    }
+}
+
+/// Tables used to decode the blocks of one component of a scan.
+struct BlockTables<'a> {
+    // Index of the component plane
+    component: usize,
+    dc_table: &'a FastHuffman,
+    ac_table: &'a FastHuffman,
+    quantization_table: &'a QuantizationTable,
+}
+
+/// Decodes the MCU at column `x` and line `y` of a scan. The MCU of a non-interleaved scan is a
+/// single block, the MCU of an interleaved scan holds `H` x `V` blocks of every component, line
+/// by line (A.2.3).
+fn decode_mcu<R: Read>(
+    reader: &mut BitReader<R>,
+    components: &mut [Component],
+    scan_components: &[BlockTables],
+    interleaved: bool,
+    x: usize,
+    y: usize,
+) -> Result<()> {
+    if !interleaved {
+        let tables = &scan_components[0];
+        return decode_block(reader, &mut components[tables.component], tables, x, y);
+    }
+
+    for tables in scan_components {
+        let component = &mut components[tables.component];
+        let horizontal_sampling_factor = component.horizontal_sampling_factor as usize;
+        let vertical_sampling_factor = component.vertical_sampling_factor as usize;
+        for v in 0..vertical_sampling_factor {
+            for h in 0..horizontal_sampling_factor {
+                decode_block(
+                    reader,
+                    component,
+                    tables,
+                    x * horizontal_sampling_factor + h,
+                    y * vertical_sampling_factor + v,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the next block of `component` and stores its samples at block column `x` and block
+/// line `y` of the component plane.
+fn decode_block<R: Read>(
+    reader: &mut BitReader<R>,
+    component: &mut Component,
+    tables: &BlockTables,
+    x: usize,
+    y: usize,
+) -> Result<()> {
+    let mut coefficients = [0; 64];
+    // Corrupt data can sum up differences beyond the range of valid coefficients
+    component.dc_predictor = component
+        .dc_predictor
+        .wrapping_add(decode_dc_difference(tables.dc_table, reader)? as i32);
+    coefficients[0] = component.dc_predictor;
+    decode_ac_block(reader, tables.ac_table, &mut coefficients)?;
+
+    let mut block = dezigzag(&coefficients);
+    dequantize(&mut block, tables.quantization_table);
+    component.store_block(x, y, &idct_8x8(&block));
+    Ok(())
+}
+
+/// Converts the pixel lines `lines` of the decoded component planes, which are grayscale for a
+/// single component, RGB for three components and CMYK for four components.
+///
+/// Only the MCU lines holding `lines` and their neighbors are upsampled, which gives the same
+/// samples as upsampling the whole planes as long as `upsampler` looks no further than one MCU
+/// line.
+fn convert_lines(
+    frame_header: &FrameHeader,
+    components: &[Component],
+    adobe_transform: Option<AdobeTransform>,
+    upsampler: &dyn Upsampler,
+    lines: Range<usize>,
+) -> Result<(Vec<u8>, PixelFormat)> {
+    let width = frame_header.max_samples_per_line as usize;
+    let (mcu_width, mcu_height) = frame_header.mcu_size();
+    let (mcus_per_line, mcu_lines) = frame_header.mcu_count();
+    let (mcu_width, mcu_height) = (mcu_width as usize, mcu_height as usize);
+
+    let first_mcu_line = (lines.start / mcu_height).saturating_sub(1);
+    let last_mcu_line = (lines.end.div_ceil(mcu_height) + 1).min(mcu_lines);
+    let planes: Vec<_> = components
+        .iter()
+        .map(|component| {
+            component.upsample(
+                mcu_width / 8,
+                mcu_height / 8,
+                first_mcu_line..last_mcu_line,
+                upsampler,
+            )
+        })
+        .collect();
+    // All planes are padded to the MCUs after upsampling
+    let stride = mcus_per_line * mcu_width;
+    let lines = lines.start - first_mcu_line * mcu_height..lines.end - first_mcu_line * mcu_height;
+
+    let (pixels, format) = match &planes[..] {
+        [gray] => {
+            let pixels = lines
+                .flat_map(|line| &gray[line * stride..line * stride + width])
+                .copied()
+                .collect();
+            (pixels, PixelFormat::Grayscale)
+        }
+        [first, second, third] => {
+            // Without an Adobe segment, three components are YCbCr as required by JFIF, unless
+            // the component IDs spell out RGB like some encoders write them
+            let ids: Vec<_> = frame_header
+                .component_headers
+                .iter()
+                .flatten()
+                .map(|component_header| component_header.id)
+                .collect();
+            let transform = adobe_transform.unwrap_or(if ids == b"RGB" {
+                AdobeTransform::Unknown
+            } else {
+                AdobeTransform::YCbCr
+            });
+            let mut pixels = Vec::with_capacity(width * lines.len() * 3);
+            for line in lines {
+                for x in 0..width {
+                    let i = line * stride + x;
+                    match transform {
+                        AdobeTransform::YCbCr => {
+                            pixels.extend(ycbcr_to_rgb(first[i], second[i], third[i]))
+                        }
+                        _ => pixels.extend([first[i], second[i], third[i]]),
+                    }
+                }
+            }
+            (pixels, PixelFormat::Rgb)
+        }
+        [first, second, third, black] => {
+            // Adobe applications write CMYK inverted, with 0 meaning full ink. Without an
+            // Adobe segment the samples are taken as they are.
+            let inverted = adobe_transform.is_some();
+            let transform = adobe_transform.unwrap_or(AdobeTransform::Unknown);
+            let mut pixels = Vec::with_capacity(width * lines.len() * 4);
+            for line in lines {
+                for x in 0..width {
+                    let i = line * stride + x;
+                    match transform {
+                        // The inverse of the YCbCr transform yields inverted CMY
+                        AdobeTransform::Ycck => {
+                            pixels.extend(ycbcr_to_rgb(first[i], second[i], third[i]));
+                            pixels.push(255 - black[i]);
+                        }
+                        _ if inverted => pixels.extend(
+                            [first[i], second[i], third[i], black[i]].map(|sample| 255 - sample),
+                        ),
+                        _ => pixels.extend([first[i], second[i], third[i], black[i]]),
+                    }
+                }
+            }
+            (pixels, PixelFormat::Cmyk)
+        }
+        _ => return Err(Error::NotImplemented("Color spaces other than YCbCr")),
+    };
+    Ok((pixels, format))
+}
+
+/// Decodes the JPEG file in `data` with the default settings of `Decoder`.
+pub fn decode_bytes(data: &[u8]) -> Result<DecodedImage> {
+    Decoder::new(io::Cursor::new(data)).decode()
+}
+
+/// Returns the JPEG thumbnail stored in an EXIF or JFIF extension APP segment.
+fn app_segment_thumbnail(n: u8, data: &[u8]) -> Result<Option<&[u8]>> {
+    match n {
+        // Thumbnail format 0x10 stores a complete JPEG stream
+        0 => match data.strip_prefix(JFXX_IDENTIFIER) {
+            Some([0x10, thumbnail @ ..]) => Ok(Some(thumbnail)),
+            _ => Ok(None),
+        },
+        1 => exif::thumbnail(data),
+        _ => Ok(None),
+    }
+}
+
+/// Splits the payload of a segment whose length field is `size` off the front of `reader`.
+fn split_segment<'a>(reader: &mut &'a [u8], size: u16) -> Result<&'a [u8]> {
+    if size < 2 {
+        return Err(Error::Parse("Segment length too small"));
+    }
+    if reader.len() < size as usize - 2 {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    let (segment, rest) = reader.split_at(size as usize - 2);
+    *reader = rest;
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::builder::JpegBuilder;
+
+    /// Inserts a segment with the code `marker` and `payload` after the SOI marker of `data`. The
+    /// length field is `length_offset` off the actual length.
+    fn insert_segment(data: &[u8], marker: u8, payload: &[u8], length_offset: i32) -> Vec<u8> {
+        let length = (payload.len() as i32 + 2 + length_offset) as u16;
+        let mut output = data[..2].to_vec();
+        output.extend([0xff, marker]);
+        output.extend(length.to_be_bytes());
+        output.extend(payload);
+        output.extend(&data[2..]);
+        output
+    }
+
+    fn jfif_payload() -> Vec<u8> {
+        let mut payload = b"JFIF\0".to_vec();
+        payload.extend([1, 2, 0, 0, 1, 0, 1, 0, 0]);
+        payload
+    }
+
+    fn gray_image() -> Vec<u8> {
+        JpegBuilder::new(16, 8)
+            .coefficients(|_, x, _| {
+                let mut block = [0; 64];
+                block[0] = [-400, 400][x];
+                block
+            })
+            .build()
+    }
+
+    #[test]
+    fn app0_length_one_byte_too_long_is_truncated_when_lenient() {
+        let expected = decode_bytes(&gray_image()).unwrap();
+        let data = insert_segment(&gray_image(), 0xe0, &jfif_payload(), 1);
+
+        let mut decoder = Decoder::new(&data[..]);
+        let image = decoder.decode().unwrap();
+        assert_eq!(image.pixels, expected.pixels);
+        assert_eq!(decoder.jfif().unwrap().version_minor, 2);
+
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_strict(true);
+        assert!(matches!(
+            decoder.decode(),
+            Err(Error::WhileParsing(_, err))
+                if matches!(*err, Error::Parse("Segment length overlaps next marker"))
+        ));
+    }
+
+    #[test]
+    fn marker_inside_payload_followed_by_padding_is_kept() {
+        // An APP1 segment carrying a whole JPEG, followed by a stray padding byte
+        let expected = decode_bytes(&gray_image()).unwrap();
+        let mut data = insert_segment(&gray_image(), 0xe1, &gray_image(), 0);
+        let app1_end = 4 + 2 + gray_image().len();
+        data.insert(app1_end, 0x00);
+
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!(image.pixels, expected.pixels);
+    }
+
+    /// Reader over `data` that returns `ErrorKind::WouldBlock` once when reaching `block_at`.
+    struct NonBlockingReader {
+        data: Vec<u8>,
+        position: usize,
+        block_at: Option<usize>,
+    }
+
+    impl Read for NonBlockingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut end = self.data.len();
+            if let Some(block_at) = self.block_at {
+                if self.position == block_at {
+                    self.block_at = None;
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                end = block_at;
+            }
+            let count = buf.len().min(end - self.position);
+            buf[..count].copy_from_slice(&self.data[self.position..self.position + count]);
+            self.position += count;
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn decode_resumes_after_would_block() {
+        let data = gray_image();
+        let expected = decode_bytes(&data).unwrap();
+        for block_at in [0, 1, 30, data.len() / 2, data.len() - 1] {
+            let mut decoder = Decoder::new(NonBlockingReader {
+                data: data.clone(),
+                position: 0,
+                block_at: Some(block_at),
+            });
+            decoder.set_nonblocking(true);
+            assert!(matches!(decoder.decode(), Err(Error::WouldBlock)));
+            let image = decoder.decode().unwrap();
+            assert_eq!(image.pixels, expected.pixels, "blocked at {}", block_at);
+            assert_eq!(
+                decoder
+                    .structure()
+                    .to_tree_string()
+                    .matches("Start of Image")
+                    .count(),
+                1
+            );
+        }
+
+        // Without being told, the decoder doesn't keep the input to start over
+        let mut decoder = Decoder::new(NonBlockingReader {
+            data,
+            position: 0,
+            block_at: Some(30),
+        });
+        assert!(matches!(decoder.decode(), Err(Error::WouldBlock)));
+        assert!(matches!(decoder.decode(), Err(Error::AlreadyConsumed)));
+    }
+
+    #[test]
+    fn second_decode_returns_the_same_image() {
+        let data = gray_image();
+        let mut decoder = Decoder::new(&data[..]);
+        let first = decoder.decode().unwrap();
+        let second = decoder.decode().unwrap();
+        assert_eq!(first.pixels, second.pixels);
+    }
+
+    #[test]
+    fn reset_decodes_the_next_image_into_the_same_planes() {
+        let color = JpegBuilder::new(33, 21)
+            .sampling_factors(&[(2, 2), (1, 1), (1, 1)])
+            .coefficients(|component, x, y| {
+                let mut block = [0; 64];
+                block[0] = 8 * (block_value(component, x, y) as i32 - 128);
+                block
+            })
+            .build();
+        let gray = gray_image();
+
+        let mut decoder = Decoder::new(&color[..]);
+        assert_eq!(
+            decoder.decode().unwrap().pixels,
+            decode_bytes(&color).unwrap().pixels
+        );
+        let capacity = decoder.image.components[2].samples.capacity();
+
+        decoder.reset(&gray[..]);
+        assert_eq!(
+            decoder.decode().unwrap().pixels,
+            decode_bytes(&gray).unwrap().pixels
+        );
+        // The gray plane fits into the buffer of the last color plane
+        assert_eq!(decoder.image.components[0].samples.capacity(), capacity);
+
+        decoder.reset(&color[..]);
+        assert_eq!(
+            decoder.decode().unwrap().pixels,
+            decode_bytes(&color).unwrap().pixels
+        );
+    }
+
+    #[test]
+    fn decode_after_failure_is_already_consumed() {
+        let data = gray_image();
+        let mut decoder = Decoder::new(&data[..data.len() / 2]);
+        decoder.set_strict(true);
+        assert!(decoder.decode().is_err());
+        assert!(matches!(decoder.decode(), Err(Error::AlreadyConsumed)));
+    }
+
+    #[test]
+    fn calls_reading_the_input_exclude_each_other() {
+        let data = gray_image();
+
+        let mut decoder = Decoder::new(&data[..]);
+        assert_eq!(decoder.extract_thumbnail().unwrap(), None);
+        assert!(matches!(decoder.decode(), Err(Error::AlreadyConsumed)));
+        decoder.reset(&data[..]);
+        assert!(decoder.decode().is_ok());
+
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+        assert!(matches!(
+            decoder.extract_thumbnail(),
+            Err(Error::AlreadyConsumed)
+        ));
+        assert!(matches!(
+            decoder.extract_all_images(),
+            Err(Error::AlreadyConsumed)
+        ));
+        assert!(matches!(
+            decoder.decode_all_frames(),
+            Err(Error::AlreadyConsumed)
+        ));
+
+        let mut decoder = Decoder::new(&data[..]);
+        assert_eq!(decoder.decode_all_frames().unwrap().len(), 1);
+        assert!(matches!(decoder.decode(), Err(Error::AlreadyConsumed)));
+    }
+
+    /// Splits a builder stream into its table segments and the rest of the image, from the
+    /// frame header to EOI.
+    fn split_tables(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut tables, mut frame) = (Vec::new(), Vec::new());
+        let mut position = 2;
+        while data[position + 1] != 0xda {
+            let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+            let segment = &data[position..position + 2 + length];
+            match data[position + 1] {
+                0xdb | 0xc4 => tables.extend(segment),
+                _ => frame.extend(segment),
+            }
+            position += 2 + length;
+        }
+        frame.extend(&data[position..]);
+        (tables, frame)
+    }
+
+    #[test]
+    fn abbreviated_stream_decodes_every_frame() {
+        let first = gray_image();
+        let second = JpegBuilder::new(8, 16).build();
+        let (tables, first_frame) = split_tables(&first);
+        let (_, second_frame) = split_tables(&second);
+
+        // Table-specification data followed by two images without tables (B.4)
+        let mut data = vec![0xff, 0xd8];
+        data.extend(tables);
+        data.extend([0xff, 0xd9]);
+        for frame in [first_frame, second_frame] {
+            data.extend([0xff, 0xd8]);
+            data.extend(frame);
+        }
+
+        let images = Decoder::new(&data[..]).decode_all_frames().unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].pixels, decode_bytes(&first).unwrap().pixels);
+        assert_eq!((images[1].width, images[1].height), (8, 16));
+        assert_eq!(images[1].pixels, vec![128; 8 * 16]);
+    }
+
+    #[test]
+    fn padding_after_the_last_frame_is_trailing() {
+        let mut data = gray_image();
+        data.extend([0; 16]);
+
+        let mut decoder = Decoder::new(&data[..]);
+        assert_eq!(decoder.decode_all_frames().unwrap().len(), 1);
+        assert_eq!(decoder.trailing_bytes().unwrap(), 16);
+
+        let mut decoder = Decoder::new(&data[..]);
+        assert!(matches!(decoder.trailing_bytes(), Err(Error::NotDecoded)));
+        // Nothing was read, the image can still be decoded
+        decoder.decode().unwrap();
+        assert_eq!(decoder.trailing_bytes().unwrap(), 16);
+        decoder.decode().unwrap();
+    }
+
+    /// Returns the payload of an MPF APP2 segment listing two images, with placeholders for
+    /// their sizes and offsets.
+    fn mpf_payload() -> Vec<u8> {
+        let mut payload = b"MPF\0II".to_vec();
+        payload.extend(42u16.to_le_bytes());
+        payload.extend(8u32.to_le_bytes());
+        // MP Index IFD with the MP entry tag, pointing behind the IFD
+        payload.extend(1u16.to_le_bytes());
+        payload.extend(0xb002u16.to_le_bytes());
+        payload.extend(7u16.to_le_bytes());
+        payload.extend(32u32.to_le_bytes());
+        payload.extend(26u32.to_le_bytes());
+        payload.extend(0u32.to_le_bytes());
+        payload.extend([0; 32]);
+        payload
+    }
+
+    #[test]
+    fn extract_all_images_splits_mpo_files() {
+        let second = JpegBuilder::new(8, 8).build();
+        let data = insert_segment(&gray_image(), 0xe2, &mpf_payload(), 0);
+        // A broken EXIF segment doesn't stop the other images from being found
+        let mut first = insert_segment(&data, 0xe1, b"Exif\0\0XX", 0);
+
+        let mp_header = first
+            .windows(4)
+            .position(|bytes| bytes == b"MPF\0")
+            .unwrap()
+            + 4;
+        let mp_entries = mp_header + 26;
+        let first_len = first.len() as u32;
+        first[mp_entries + 4..mp_entries + 8].copy_from_slice(&first_len.to_le_bytes());
+        let second_size = (second.len() as u32).to_le_bytes();
+        let second_offset = ((first.len() - mp_header) as u32).to_le_bytes();
+        first[mp_entries + 20..mp_entries + 24].copy_from_slice(&second_size);
+        first[mp_entries + 24..mp_entries + 28].copy_from_slice(&second_offset);
+
+        let mut data = first.clone();
+        data.extend(&second);
+        let images = Decoder::new(&data[..]).extract_all_images().unwrap();
+        assert_eq!(images, vec![first, second]);
+    }
+
+    /// Reader which counts the bytes read through it.
+    struct CountingReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.data[self.position..].as_ref().read(buf)?;
+            self.position += count;
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn extract_thumbnail_stops_before_the_scan() {
+        let thumbnail = JpegBuilder::new(8, 8).build();
+        let image = JpegBuilder::new(256, 256)
+            .coefficients(|_, x, y| {
+                let mut block = [0; 64];
+                block[..8].copy_from_slice(&[(x * y % 50) as i32 - 25; 8]);
+                block
+            })
+            .build();
+        // The scan data makes up most of the image
+        let scan_start = image
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xda])
+            .unwrap();
+        assert!(image.len() - scan_start > 1000);
+
+        let mut reader = CountingReader {
+            data: &image,
+            position: 0,
+        };
+        assert_eq!(Decoder::new(&mut reader).extract_thumbnail().unwrap(), None);
+        // Only the marker and length of the SOS segment are read
+        assert_eq!(reader.position, scan_start + 4);
+
+        let mut jfxx = b"JFXX\0\x10".to_vec();
+        jfxx.extend(&thumbnail);
+        let data = insert_segment(&image, 0xe0, &jfxx, 0);
+        // A broken EXIF segment in front of the JFXX one is skipped
+        let data = insert_segment(&data, 0xe1, b"Exif\0\0XX", 0);
+
+        let mut reader = CountingReader {
+            data: &data,
+            position: 0,
+        };
+        let extracted = Decoder::new(&mut reader).extract_thumbnail().unwrap();
+        assert_eq!(extracted, Some(thumbnail));
+        assert!(reader.position < data.len() - image.len() + scan_start);
+    }
+
+    #[test]
+    fn io_error_in_huffman_table_names_the_stage() {
+        use std::error::Error as _;
+
+        let data = gray_image();
+        let dht = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc4])
+            .unwrap();
+        let err = decode_bytes(&data[..dht + 10]).unwrap_err();
+
+        assert_eq!(err.to_string(), "while reading Huffman table");
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "I/O error");
+        let io_error = source
+            .source()
+            .unwrap()
+            .downcast_ref::<io::Error>()
+            .unwrap();
+        assert_eq!(io_error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn hierarchical_frames_are_not_implemented() {
+        for code in [0xc5, 0xc6, 0xc7, 0xcd, 0xce, 0xcf] {
+            let mut data = gray_image();
+            let sof = data
+                .windows(2)
+                .position(|bytes| bytes == [0xff, 0xc0])
+                .unwrap();
+            data[sof + 1] = code;
+
+            let err = decode_bytes(&data).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    Error::WhileParsing(_, ref err)
+                        if matches!(**err, Error::NotImplemented("Hierarchical decoding"))
+                ),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    /// Sample value of the flat block at block column `x` and block line `y` of `component`.
+    fn block_value(component: usize, x: usize, y: usize) -> u8 {
+        (16 + (x * 37 + y * 59 + component * 83) % 224) as u8
+    }
+
+    /// Decodes an image of flat blocks with the given sampling factors and checks that every
+    /// pixel gets the samples of the blocks covering it.
+    fn check_layout(width: u16, height: u16, sampling_factors: &[(u8, u8)]) {
+        let data = JpegBuilder::new(width, height)
+            .sampling_factors(sampling_factors)
+            .coefficients(|component, x, y| {
+                let mut block = [0; 64];
+                block[0] = 8 * (block_value(component, x, y) as i32 - 128);
+                block
+            })
+            .build();
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!((image.width, image.height), (width, height));
+
+        let max_horizontal = sampling_factors.iter().map(|f| f.0 as usize).max().unwrap();
+        let max_vertical = sampling_factors.iter().map(|f| f.1 as usize).max().unwrap();
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                let [luma, blue, red] = [0, 1, 2].map(|component| {
+                    let (horizontal, vertical) = sampling_factors[component];
+                    let component_x = x * horizontal as usize / max_horizontal;
+                    let component_y = y * vertical as usize / max_vertical;
+                    block_value(component, component_x / 8, component_y / 8)
+                });
+                let i = (y * width as usize + x) * 3;
+                assert_eq!(
+                    image.pixels[i..i + 3],
+                    ycbcr_to_rgb(luma, blue, red),
+                    "{:?} at {}x{} pixel ({}, {})",
+                    sampling_factors,
+                    width,
+                    height,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn subsampled_layouts_place_blocks_at_odd_sizes() {
+        let layouts: [&[(u8, u8)]; 5] = [
+            // 4:2:2 and 4:4:0, whose MCUs aren't square
+            &[(2, 1), (1, 1), (1, 1)],
+            &[(1, 2), (1, 1), (1, 1)],
+            // 4:1:1 and 4:2:0
+            &[(4, 1), (1, 1), (1, 1)],
+            &[(2, 2), (1, 1), (1, 1)],
+            // Chroma sampled more densely than luma
+            &[(1, 1), (2, 2), (2, 1)],
+        ];
+        for sampling_factors in layouts {
+            check_layout(33, 21, sampling_factors);
+            check_layout(17, 9, sampling_factors);
+        }
+    }
+
+    #[test]
+    fn dc_only_scan_decodes_to_flat_blocks() {
+        // Alternating extremes make the DC differences span the whole range of categories
+        let value = |x: usize, y: usize| [0, 255, 1, 254, 128][(x + 2 * y) % 5];
+        let data = JpegBuilder::new(30, 17)
+            .coefficients(move |_, x, y| {
+                let mut block = [0; 64];
+                block[0] = 8 * (value(x, y) as i32 - 128);
+                block
+            })
+            .build();
+
+        let image = decode_bytes(&data).unwrap();
+        for (i, pixel) in image.pixels.iter().enumerate() {
+            let (x, y) = (i % 30, i / 30);
+            assert_eq!(*pixel, value(x / 8, y / 8), "pixel ({}, {})", x, y);
+        }
+    }
+
+    /// A grayscale image of four blocks in a row with a restart marker after every `interval`
+    /// blocks. The blocks carry AC coefficients, so every interval spans several bytes.
+    fn restart_image(interval: u16) -> Vec<u8> {
+        JpegBuilder::new(32, 8)
+            .restart_interval(interval)
+            .coefficients(|_, x, _| {
+                let mut block = [0; 64];
+                block[0] = 8 * (block_value(0, x, 0) as i32 - 128);
+                block[1] = 40;
+                block[8] = -40;
+                block
+            })
+            .build()
+    }
+
+    /// Position of the restart marker RSTn in `data`.
+    fn restart_marker(data: &[u8], n: u8) -> usize {
+        data.windows(2)
+            .position(|bytes| bytes == [0xff, 0xd0 + n])
+            .unwrap()
+    }
+
+    /// Pixels of block column `x` in a 32x8 image.
+    fn block_pixels(pixels: &[u8], x: usize) -> Vec<u8> {
+        (0..8)
+            .flat_map(|y| &pixels[y * 32 + x * 8..y * 32 + x * 8 + 8])
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn restart_intervals_reset_the_prediction() {
+        let with_restarts = decode_bytes(&restart_image(2)).unwrap();
+        let without_restarts = decode_bytes(&restart_image(0)).unwrap();
+        assert_eq!(with_restarts.pixels, without_restarts.pixels);
+
+        // The DC levels only come out right if the prediction restarts after RST0
+        for x in 0..4 {
+            let pixels = block_pixels(&with_restarts.pixels, x);
+            let mean = pixels.iter().map(|p| *p as usize).sum::<usize>() / 64;
+            assert!(
+                mean.abs_diff(block_value(0, x, 0) as usize) <= 1,
+                "block {}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_restart_marker_is_rejected() {
+        let mut data = restart_image(2);
+        let rst0 = restart_marker(&data, 0);
+        data[rst0 + 1] = 0xd3;
+
+        let err = decode_bytes(&data).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::WhileParsing(_, ref err)
+                    if matches!(**err, Error::Parse("Unexpected restart marker"))
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn decoding_resumes_after_an_early_restart_marker() {
+        let expected = decode_bytes(&restart_image(1)).unwrap();
+
+        // Drop the data of the second interval, so RST1 follows right after RST0
+        let mut data = restart_image(1);
+        let (rst0, rst1) = (restart_marker(&data, 0), restart_marker(&data, 1));
+        data.drain(rst0 + 2..rst1);
+
+        let image = decode_bytes(&data).unwrap();
+        for x in [0, 2, 3] {
+            assert_eq!(
+                block_pixels(&image.pixels, x),
+                block_pixels(&expected.pixels, x),
+                "block {}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn decode_rows_hands_out_the_lines_of_decode() {
+        use crate::jpeg::upsampler::TriangleUpsampler;
+
+        let (width, height) = (45, 100);
+        let layouts: [&[(u8, u8)]; 3] = [&[(2, 2), (1, 1), (1, 1)], &[(1, 1)], &[(2, 2)]];
+        for sampling_factors in layouts {
+            let data = JpegBuilder::new(width, height)
+                .sampling_factors(sampling_factors)
+                .coefficients(|component, x, y| {
+                    let mut block = [0; 64];
+                    block[0] = 8 * (block_value(component, x, y) as i32 - 128);
+                    block
+                })
+                .build();
+            let mut decoder = Decoder::new(&data[..]);
+            decoder.set_upsampler(Box::new(TriangleUpsampler));
+            let expected = decoder.decode().unwrap();
+            // After `decode`, the lines of the kept image are handed out
+            let mut pixels = Vec::new();
+            decoder
+                .decode_rows(|_, row| pixels.extend_from_slice(row))
+                .unwrap();
+            assert_eq!(pixels, expected.pixels);
+
+            let mut decoder = Decoder::new(&data[..]);
+            decoder.set_upsampler(Box::new(TriangleUpsampler));
+            let (mut lines, mut pixels) = (Vec::new(), Vec::new());
+            decoder
+                .decode_rows(|line, row| {
+                    lines.push(line);
+                    pixels.extend_from_slice(row);
+                })
+                .unwrap();
+            assert_eq!(lines, (0..height).collect::<Vec<_>>());
+            assert_eq!(pixels, expected.pixels, "{:?}", sampling_factors);
+
+            // The planes only held the MCU lines around the converted one
+            for component in &decoder.image.components {
+                assert!(component.samples.len() <= STREAMING_WINDOW * component.mcu_line_samples());
+            }
+            assert!(matches!(decoder.decode(), Err(Error::AlreadyConsumed)));
+        }
+    }
+
+    fn adobe_payload(transform: u8) -> Vec<u8> {
+        let mut payload = b"Adobe".to_vec();
+        payload.extend(100u16.to_be_bytes());
+        payload.extend([0, 0, 0, 0, transform]);
+        payload
+    }
+
+    /// An 8x8 image with four components, whose blocks decode to `samples`.
+    fn four_component_image(samples: [u8; 4]) -> Vec<u8> {
+        JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1); 4])
+            .coefficients(move |component, _, _| {
+                let mut block = [0; 64];
+                block[0] = 8 * (samples[component] as i32 - 128);
+                block
+            })
+            .build()
+    }
+
+    #[test]
+    fn cmyk_without_adobe_segment_is_taken_as_it_is() {
+        let image = decode_bytes(&four_component_image([200, 100, 30, 60])).unwrap();
+        assert_eq!(image.format, PixelFormat::Cmyk);
+        assert_eq!(image.pixels, [200, 100, 30, 60].repeat(64));
+    }
+
+    #[test]
+    fn adobe_cmyk_is_inverted() {
+        // Adobe writes 255 for no ink, so a light K sample of 195 is 60 after inversion
+        let data = insert_segment(
+            &four_component_image([55, 155, 225, 195]),
+            0xee,
+            &adobe_payload(0),
+            0,
+        );
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!(image.format, PixelFormat::Cmyk);
+        assert_eq!(image.pixels, [200, 100, 30, 60].repeat(64));
+    }
+
+    #[test]
+    fn ycck_is_converted_to_cmyk() {
+        let data = insert_segment(
+            &four_component_image([150, 100, 180, 195]),
+            0xee,
+            &adobe_payload(2),
+            0,
+        );
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!(image.format, PixelFormat::Cmyk);
+
+        // The YCbCr part yields inverted CMY, K is inverted like Adobe CMYK
+        let [c, m, y] = ycbcr_to_rgb(150, 100, 180).map(|sample| 255 - sample);
+        let expected = [255 - c, 255 - m, 255 - y, 60];
+        assert!(image.pixels.chunks_exact(4).all(|pixel| pixel == expected));
+    }
+
+    #[test]
+    fn plane_limit_is_checked_before_allocating() {
+        let data = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(4, 4), (1, 1), (1, 1)])
+            .build();
+        let limit_exceeded = |result: Result<DecodedImage>| {
+            matches!(
+                result,
+                Err(Error::WhileParsing("reading frame header", err))
+                    if matches!(*err, Error::LimitExceeded(_))
+            )
+        };
+
+        // A single MCU of 32x32 samples holds the largest plane
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_max_plane_samples(32 * 32);
+        assert!(decoder.decode().is_ok());
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_max_plane_samples(32 * 32 - 1);
+        assert!(limit_exceeded(decoder.decode()));
+
+        // The largest frame with the largest sampling factors would need 4 GiB for the luma plane
+        let mut data = data;
+        let sof = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+        data[sof + 5..sof + 9].copy_from_slice(&[0xff; 4]);
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_max_plane_samples(1 << 24);
+        assert!(limit_exceeded(decoder.decode()));
+    }
+
+    /// Returns the message of the parse error `data` fails to decode with.
+    fn parse_error(data: &[u8]) -> &'static str {
+        match decode_bytes(data) {
+            Err(Error::WhileParsing(_, err)) => match *err {
+                Error::Parse(message) => message,
+                err => panic!("{:?}", err),
+            },
+            result => panic!("{:?}", result.map(|image| image.format)),
+        }
+    }
+
+    #[test]
+    fn scan_components_are_checked_against_the_frame() {
+        let data = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1), (1, 1), (1, 1)])
+            .build();
+        let sos = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xda])
+            .unwrap();
+
+        // Select the second component twice instead of the second and the third one
+        let mut duplicate = data.clone();
+        assert_eq!(duplicate[sos + 9], 3);
+        duplicate[sos + 9] = 2;
+        assert_eq!(parse_error(&duplicate), "Duplicate scan component selector");
+
+        let mut data = gray_image();
+        let sos = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xda])
+            .unwrap();
+        data[sos + 4] = 2;
+        assert_eq!(parse_error(&data), "Scan has more components than frame");
+    }
+
+    #[test]
+    fn quantization_table_selector_above_three_is_rejected() {
+        let mut data = gray_image();
+        let sof = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+        data[sof + 12] = 5;
+
+        assert_eq!(parse_error(&data), "Invalid quantization table selector");
+    }
+
+    #[test]
+    fn sampling_factor_zero_is_rejected() {
+        for sampling_factor in [0x01, 0x10] {
+            let mut data = gray_image();
+            let sof = data
+                .windows(2)
+                .position(|bytes| bytes == [0xff, 0xc0])
+                .unwrap();
+            data[sof + 11] = sampling_factor;
+
+            assert_eq!(parse_error(&data), "Invalid sampling factor");
+        }
+    }
+
+    /// Returns a DHT payload for the DC table 0 with `counts` codes of each length.
+    fn huffman_payload(counts: [u8; 16], values: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0x00];
+        payload.extend(counts);
+        payload.extend(values);
+        payload
+    }
+
+    #[test]
+    fn huffman_values_have_to_fill_the_segment() {
+        let mut counts = [0; 16];
+        counts[1] = 2;
+
+        let too_many = huffman_payload(counts, &[0, 1, 2]);
+        assert_eq!(
+            parse_error(&insert_segment(&gray_image(), 0xc4, &too_many, 0)),
+            "Huffman table segment has trailing bytes"
+        );
+
+        let too_few = huffman_payload(counts, &[0]);
+        assert_eq!(
+            parse_error(&insert_segment(&gray_image(), 0xc4, &too_few, 0)),
+            "Huffman table values exceed segment length"
+        );
+    }
+
+    #[test]
+    fn huffman_table_with_more_than_256_values_is_rejected() {
+        // 16 * 17 = 272 codes, followed by as many values
+        let payload = huffman_payload([17; 16], &[0; 272]);
+
+        assert_eq!(
+            parse_error(&insert_segment(&gray_image(), 0xc4, &payload, 0)),
+            "Huffman table has more than 256 values"
+        );
+    }
+
+    #[test]
+    fn quantization_table_with_zero_element_is_rejected() {
+        let mut payload = vec![0x00];
+        payload.extend([1; 64]);
+        payload[10] = 0;
+
+        assert_eq!(
+            parse_error(&insert_segment(&gray_image(), 0xdb, &payload, 0)),
+            "Quantization table has zero element"
+        );
+    }
+
+    #[test]
+    fn redefined_table_uses_the_new_precision() {
+        let data = JpegBuilder::new(8, 8)
+            .coefficients(|_, _, _| {
+                let mut block = [0; 64];
+                block[0] = 2;
+                block
+            })
+            .build();
+
+        // Redefine the 8-bit table 0 of the builder with 16-bit steps larger than a byte
+        let mut payload = vec![0x10];
+        for _ in 0..64 {
+            payload.extend(320u16.to_be_bytes());
+        }
+        let sof = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+        let mut redefined = data[..sof].to_vec();
+        redefined.extend([0xff, 0xdb]);
+        redefined.extend((payload.len() as u16 + 2).to_be_bytes());
+        redefined.extend(payload);
+        redefined.extend(&data[sof..]);
+
+        assert_eq!(decode_bytes(&data).unwrap().pixels, [128; 64]);
+        // A DC coefficient of 2 * 320 raises the samples by 2 * 320 / 8
+        assert_eq!(decode_bytes(&redefined).unwrap().pixels, [208; 64]);
+    }
+
+    #[test]
+    fn invalid_jfxx_thumbnail_format_is_rejected() {
+        let data = insert_segment(&gray_image(), 0xe0, b"JFXX\0\x12", 0);
+
+        assert_eq!(parse_error(&data), "Invalid JFXX thumbnail format");
+    }
+
+    #[test]
+    fn comment_which_is_not_utf8_is_latin1() {
+        let data = insert_segment(&gray_image(), 0xfe, b"Caf\xe9", 0);
+        let data = insert_segment(&data, 0xfe, "Café".as_bytes(), 0);
+
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+        let expected = [
+            Comment {
+                text: "Café".to_string(),
+                encoding: CommentEncoding::Utf8,
+            },
+            Comment {
+                text: "Café".to_string(),
+                encoding: CommentEncoding::Latin1,
+            },
+        ];
+        assert_eq!(decoder.comments(), expected);
+    }
+
+    #[test]
+    fn photoshop_resources_are_collected_from_app13() {
+        let data = insert_segment(&gray_image(), 0xed, b"Photoshop 3.0\08BIM\x04\x04", 0);
+        let data = insert_segment(&data, 0xed, b"Adobe_CM\0\x01", 0);
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+        assert_eq!(decoder.photoshop_irb(), Some(&b"8BIM\x04\x04"[..]));
+
+        let data = insert_segment(&gray_image(), 0xed, b"Adobe_CM\0\x01", 0);
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+        assert_eq!(decoder.photoshop_irb(), None);
+    }
+
+    #[test]
+    fn table_defined_twice_in_one_segment_is_a_warning() {
+        let mut counts = [0; 16];
+        counts[0] = 1;
+        let mut payload = huffman_payload(counts, &[0]);
+        payload.extend(huffman_payload(counts, &[1]));
+        let data = insert_segment(&gray_image(), 0xc4, &payload, 0);
+
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+        assert_eq!(
+            decoder.warnings(),
+            [Warning::DuplicateHuffmanTable { class: 0, id: 0 }]
+        );
+    }
+
+    #[test]
+    fn quantization_table_may_follow_the_frame_header() {
+        let data = gray_image();
+        let expected = decode_bytes(&data).unwrap();
+
+        // The builder writes SOI, DQT and SOF in that order
+        let sof = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+        let dht = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc4])
+            .unwrap();
+        let mut reordered = data[..2].to_vec();
+        reordered.extend(&data[sof..dht]);
+        reordered.extend(&data[2..sof]);
+        reordered.extend(&data[dht..]);
+
+        assert_eq!(decode_bytes(&reordered).unwrap(), expected);
+    }
+
+    /// Turns `data` of the builder into a progressive stream whose only scan holds the DC
+    /// coefficients. Returns the stream and the position of the SOS marker.
+    fn progressive_image(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut data = data.to_vec();
+        let sof = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+        data[sof + 1] = 0xc2;
+        let sos = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xda])
+            .unwrap();
+        let count = data[sos + 4] as usize;
+        // Spectral selection 0..=0
+        data[sos + 6 + 2 * count] = 0;
+        (data, sos)
+    }
+
+    #[test]
+    fn progressive_decoding_is_not_implemented() {
+        let (data, _) = progressive_image(&gray_image());
+
+        let err = decode_bytes(&data).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::WhileParsing(_, ref err)
+                    if matches!(**err, Error::NotImplemented("Progressive DCT decoding"))
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn progressive_scans_check_the_spectral_selection() {
+        // Spectral selection 1..=0
+        let (mut data, sos) = progressive_image(&gray_image());
+        data[sos + 7] = 1;
+        assert_eq!(parse_error(&data), "Invalid spectral selection");
+
+        // Spectral selection 1..=63 over all three components
+        let color = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1), (1, 1), (1, 1)])
+            .build();
+        let (mut data, sos) = progressive_image(&color);
+        data[sos + 11..sos + 13].copy_from_slice(&[1, 63]);
+        assert_eq!(
+            parse_error(&data),
+            "Progressive AC scan must contain one component"
+        );
+    }
+
+    #[test]
+    fn scans_report_the_tables_of_each_component() {
+        let data = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1), (1, 1), (1, 1)])
+            .build();
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+
+        // The builder codes luma with tables 0 and chroma with tables 1
+        let tables = |component_id, table| ScanComponentTables {
+            component_id,
+            dc_table: table,
+            ac_table: table,
+            quantization_table: 0,
+        };
+        assert_eq!(
+            decoder.scans(),
+            [ScanInfo {
+                components: vec![tables(1, 0), tables(2, 1), tables(3, 1)],
+            }]
+        );
+    }
+
+    #[test]
+    fn crop_rectangles_are_checked_against_the_mcu_grid() {
+        // 4:2:0 with MCUs of 16x16 pixels, the last MCU column and line are padded
+        let data = JpegBuilder::new(40, 40)
+            .sampling_factors(&[(2, 2), (1, 1), (1, 1)])
+            .build();
+        let mut decoder = Decoder::new(&data[..]);
+        assert_eq!(decoder.is_mcu_aligned(0, 0, 16, 16), None);
+        decoder.decode().unwrap();
+
+        assert_eq!(decoder.is_mcu_aligned(16, 16, 16, 16), Some(true));
+        assert_eq!(decoder.is_mcu_aligned(0, 0, 10, 16), Some(false));
+        assert_eq!(decoder.is_mcu_aligned(10, 0, 16, 16), Some(false));
+        // Edges at the border of the image don't need to be aligned
+        assert_eq!(decoder.is_mcu_aligned(16, 32, 24, 8), Some(true));
+        assert_eq!(decoder.is_mcu_aligned(16, 32, 32, 8), Some(false));
+    }
+
+    #[test]
+    fn rgb_components_are_not_converted() {
+        let data = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1); 3])
+            .coefficients(|component, _, _| {
+                let mut block = [0; 64];
+                block[0] = 8 * ([200, 100, 30][component] - 128);
+                block
+            })
+            .build();
+        let ycbcr = decode_bytes(&data).unwrap();
+        assert_eq!(ycbcr.pixels[..3], ycbcr_to_rgb(200, 100, 30));
+
+        // Component IDs 'R', 'G' and 'B' in the frame and the scan header
+        let mut rgb = data.clone();
+        let sof = rgb
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+        let sos = rgb
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xda])
+            .unwrap();
+        for (i, id) in b"RGB".iter().enumerate() {
+            rgb[sof + 10 + 3 * i] = *id;
+            rgb[sos + 5 + 2 * i] = *id;
+        }
+        let image = decode_bytes(&rgb).unwrap();
+        assert_eq!(image.format, PixelFormat::Rgb);
+        assert_eq!(image.pixels, [200, 100, 30].repeat(64));
+
+        // Adobe transform 0 with the usual IDs
+        let data = insert_segment(&data, 0xee, &adobe_payload(0), 0);
+        let image = decode_bytes(&data).unwrap();
+        assert_eq!(image.pixels, [200, 100, 30].repeat(64));
     }
 }
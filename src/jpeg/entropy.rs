@@ -0,0 +1,148 @@
+use super::bitreader::BitReader;
+use super::error::{Error, Result};
+use super::huffman_tree::HuffmanDecoder;
+use std::io::Read;
+
+/// Decodes the difference of a DC coefficient to the DC coefficient of the previous block of
+/// the same component (F.2.2.1).
+pub fn decode_dc_difference<R: Read>(
+    tree: &impl HuffmanDecoder,
+    reader: &mut BitReader<R>,
+) -> Result<i16> {
+    // The decoded symbol is the number of additional bits, which is at most 11 for 8-bit
+    // samples (Table F.1)
+    let category = tree.decode(reader)?;
+    if category > 11 {
+        return Err(Error::Parse("Invalid DC difference category"));
+    }
+
+    let bits = reader.read_bits(category)?;
+    Ok(extend(bits, category))
+}
+
+/// Converts the `category` additional bits of a coefficient to its signed value (Figure F.12).
+/// Values below half the range of the category are negative.
+pub fn extend(bits: u16, category: u8) -> i16 {
+    let bits = bits as i32;
+    if category == 0 {
+        0
+    } else if bits < 1 << (category - 1) {
+        (bits - (1 << category) + 1) as i16
+    } else {
+        bits as i16
+    }
+}
+
+/// Decodes the AC coefficients of a block into positions 1 to 63 of `out`, which is in zig-zag
+/// order (F.2.2.2). Coefficients that aren't coded are left untouched.
+pub fn decode_ac_block<R: Read>(
+    reader: &mut BitReader<R>,
+    tree: &impl HuffmanDecoder,
+    out: &mut [i32; 64],
+) -> Result<()> {
+    let mut k = 1;
+    while k < 64 {
+        // The high nibble is the number of zero coefficients preceding the coefficient, the low
+        // nibble the number of its additional bits
+        let symbol = tree.decode(reader)?;
+        let run = (symbol >> 4) as usize;
+        let size = symbol & 0x0f;
+
+        match (run, size) {
+            // End of block, the remaining coefficients are zero
+            (0, 0) => break,
+            // Run of 16 zero coefficients
+            (15, 0) => {
+                k += 16;
+                if k > 64 {
+                    return Err(Error::Parse("AC coefficient run exceeds block"));
+                }
+            }
+            (_, 0) => return Err(Error::Parse("Invalid AC coefficient symbol")),
+            // The additional bits are at most 10 for 8-bit samples (Table F.2)
+            (_, 11..) => return Err(Error::Parse("Invalid AC coefficient category")),
+            _ => {
+                k += run;
+                if k > 63 {
+                    return Err(Error::Parse("AC coefficient run exceeds block"));
+                }
+                out[k] = extend(reader.read_bits(size)?, size) as i32;
+                k += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::huffman_tree::HuffmanTree;
+    use crate::jpeg::tables::{huffman_table, STD_DC_LUMA_BITS, STD_DC_LUMA_VALUES};
+
+    fn dc_tree() -> HuffmanTree {
+        HuffmanTree::new(&huffman_table(&STD_DC_LUMA_BITS, &STD_DC_LUMA_VALUES)).unwrap()
+    }
+
+    #[test]
+    fn extend_maps_low_values_to_negative_ones() {
+        assert_eq!(extend(0, 0), 0);
+        assert_eq!(extend(0, 1), -1);
+        assert_eq!(extend(1, 1), 1);
+        assert_eq!(extend(0b00, 2), -3);
+        assert_eq!(extend(0b01, 2), -2);
+        assert_eq!(extend(0b10, 2), 2);
+        assert_eq!(extend(0, 11), -2047);
+        assert_eq!(extend(0x7ff, 11), 2047);
+    }
+
+    #[test]
+    fn dc_category_zero_is_a_zero_difference() {
+        // Category 0 is coded as `00` in Table K.3 and has no additional bits
+        let data = [0b0011_1111, 0xff, 0xd9];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(decode_dc_difference(&dc_tree(), &mut reader).unwrap(), 0);
+        assert_eq!(reader.read_bits(6).unwrap(), 0b11_1111);
+    }
+
+    #[test]
+    fn dc_difference_extends_negative_values() {
+        // Category 3 is coded as `100`, the additional bits `010` are -5
+        let data = [0b1000_1011, 0xff, 0xd9];
+        let mut reader = BitReader::new(&data[..]);
+        assert_eq!(decode_dc_difference(&dc_tree(), &mut reader).unwrap(), -5);
+    }
+
+    /// AC table with the codes `0` for EOB, `10` for ZRL, `110` for a run of one zero followed by
+    /// a coefficient with one additional bit, and `1110` for a run of three and two bits.
+    fn ac_tree() -> HuffmanTree {
+        let mut bits = [0; 16];
+        bits[..4].copy_from_slice(&[1, 1, 1, 1]);
+        HuffmanTree::new(&huffman_table(&bits, &[0x00, 0xf0, 0x11, 0x32])).unwrap()
+    }
+
+    #[test]
+    fn zero_run_and_early_end_of_block() {
+        // ZRL, a run of one followed by the coefficient 1, then EOB
+        let data = [0b1011_0101, 0xff, 0xd9];
+        let mut reader = BitReader::new(&data[..]);
+        let mut out = [0; 64];
+        out[0] = 42;
+        decode_ac_block(&mut reader, &ac_tree(), &mut out).unwrap();
+
+        let mut expected = [0; 64];
+        expected[0] = 42;
+        expected[18] = 1;
+        assert_eq!(out, expected);
+        assert_eq!(reader.read_bits(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn zero_run_past_the_block_is_rejected() {
+        // Four ZRLs skip 64 coefficients, one more than the block holds
+        let data = [0b1010_1010, 0xff, 0xd9];
+        let mut reader = BitReader::new(&data[..]);
+        let result = decode_ac_block(&mut reader, &ac_tree(), &mut [0; 64]);
+        assert!(matches!(result, Err(Error::Parse(_))));
+    }
+}
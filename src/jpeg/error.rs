@@ -1,17 +1,102 @@
 use core::result;
+use std::fmt;
 use std::io;
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    /// The file uses something the specification doesn't allow.
     Unsupported(&'static str),
+    /// The file is valid, but uses a feature this decoder doesn't implement yet.
+    NotImplemented(&'static str),
+    /// The underlying reader is non-blocking and has no data available right now. If the decoder
+    /// was told with `Decoder::set_nonblocking`, it kept the bytes consumed so far and the call
+    /// can be repeated once more data has arrived. Otherwise the decoder has to be reset.
+    WouldBlock,
+    /// The input was already read by a previous call to the decoder that failed or doesn't keep
+    /// the image, like `Decoder::extract_thumbnail`. The decoder has to be reset before it can
+    /// decode again.
+    AlreadyConsumed,
+    /// The call needs the input to be decoded by a previous call first, like
+    /// `Decoder::trailing_bytes`.
+    NotDecoded,
+    /// Decoding the file would exceed a limit set on the decoder.
+    LimitExceeded(&'static str),
     Io(io::Error),
     Parse(&'static str),
+    /// The error happened at the stage of decoding named by the first field, like "reading
+    /// Huffman table".
+    WhileParsing(&'static str, Box<Error>),
+}
+
+/// Adds the stage of decoding to errors, see `Error::WhileParsing`.
+pub(crate) trait Context {
+    fn context(self, stage: &'static str) -> Self;
+}
+
+impl<T> Context for Result<T> {
+    fn context(self, stage: &'static str) -> Self {
+        self.map_err(|err| match err {
+            // Not a failure, callers match on it to retry once more data has arrived
+            Error::WouldBlock => Error::WouldBlock,
+            err => Error::WhileParsing(stage, Box::new(err)),
+        })
+    }
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
-        Error::Io(err)
+        match err.kind() {
+            io::ErrorKind::WouldBlock => Error::WouldBlock,
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsupported(message) => write!(f, "unsupported JPEG: {}", message),
+            Error::NotImplemented(feature) => write!(f, "not implemented: {}", feature),
+            Error::WouldBlock => write!(f, "reader has no data available right now"),
+            Error::AlreadyConsumed => write!(f, "decoder input already consumed"),
+            Error::NotDecoded => write!(f, "decoder input not decoded yet"),
+            Error::LimitExceeded(limit) => write!(f, "limit exceeded: {}", limit),
+            // The wrapped errors are the source, error reporters print them after this
+            Error::Io(_) => write!(f, "I/O error"),
+            Error::Parse(message) => write!(f, "invalid JPEG: {}", message),
+            Error::WhileParsing(stage, _) => write!(f, "while {}", stage),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::WhileParsing(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn io_error_is_the_source() {
+        let err = Error::from(io::Error::other("disk full"));
+        assert_eq!(err.to_string(), "I/O error");
+        assert_eq!(err.source().unwrap().to_string(), "disk full");
+    }
+
+    #[test]
+    fn would_block_is_distinct() {
+        let err = Error::from(io::Error::from(io::ErrorKind::WouldBlock));
+        assert!(matches!(err, Error::WouldBlock));
+        assert!(err.source().is_none());
     }
 }
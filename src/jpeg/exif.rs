@@ -0,0 +1,187 @@
+use super::error::{Error, Result};
+
+const EXIF_IDENTIFIER: &[u8] = b"Exif\0\0";
+
+// TIFF tags of IFD1 that locate the thumbnail (EXIF 2.3, section 4.6.3)
+const TAG_JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+
+/// Locates the JPEG thumbnail in the payload of an EXIF APP1 segment.
+///
+/// The payload holds a TIFF structure after the `"Exif\0\0"` identifier. Its first IFD describes
+/// the main image, the second one (IFD1) the thumbnail. Returns `None` if the payload isn't EXIF
+/// or doesn't contain a JPEG thumbnail.
+pub fn thumbnail(app1: &[u8]) -> Result<Option<&[u8]>> {
+    if !app1.starts_with(EXIF_IDENTIFIER) {
+        return Ok(None);
+    }
+    let tiff = Tiff::new(&app1[EXIF_IDENTIFIER.len()..])?;
+
+    let ifd0 = tiff.u32_at(4)? as usize;
+    let ifd0_entries_count = tiff.u16_at(ifd0)? as usize;
+    let ifd1 = tiff.u32_at(ifd0 + 2 + ifd0_entries_count * 12)? as usize;
+    if ifd1 == 0 {
+        return Ok(None);
+    }
+
+    let mut offset = None;
+    let mut length = None;
+    let ifd1_entries_count = tiff.u16_at(ifd1)? as usize;
+    for i in 0..ifd1_entries_count {
+        // Each entry consists of tag, type, count and value, the value being stored inline
+        // because it fits into four bytes
+        let entry = ifd1 + 2 + i * 12;
+        match tiff.u16_at(entry)? {
+            TAG_JPEG_INTERCHANGE_FORMAT => offset = Some(tiff.u32_at(entry + 8)? as usize),
+            TAG_JPEG_INTERCHANGE_FORMAT_LENGTH => length = Some(tiff.u32_at(entry + 8)? as usize),
+            _ => {}
+        }
+    }
+
+    match (offset, length) {
+        (Some(offset), Some(length)) => tiff
+            .data
+            .get(offset..offset + length)
+            .map(Some)
+            .ok_or(Error::Parse("EXIF thumbnail out of bounds")),
+        _ => Ok(None),
+    }
+}
+
+pub(super) struct Tiff<'a> {
+    pub(super) data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Result<Self> {
+        // TIFF header: byte order, the magic number 42 and the offset of IFD0
+        let big_endian = match data.get(0..2) {
+            Some(b"II") => false,
+            Some(b"MM") => true,
+            _ => return Err(Error::Parse("Invalid TIFF byte order")),
+        };
+
+        let tiff = Self { data, big_endian };
+        if tiff.u16_at(2)? != 42 {
+            return Err(Error::Parse("Invalid TIFF header"));
+        }
+        Ok(tiff)
+    }
+
+    pub(super) fn u16_at(&self, offset: usize) -> Result<u16> {
+        let bytes = self.bytes_at(offset)?;
+        if self.big_endian {
+            Ok(u16::from_be_bytes(bytes))
+        } else {
+            Ok(u16::from_le_bytes(bytes))
+        }
+    }
+
+    pub(super) fn u32_at(&self, offset: usize) -> Result<u32> {
+        let bytes = self.bytes_at(offset)?;
+        if self.big_endian {
+            Ok(u32::from_be_bytes(bytes))
+        } else {
+            Ok(u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn bytes_at<const N: usize>(&self, offset: usize) -> Result<[u8; N]> {
+        self.data
+            .get(offset..offset + N)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(Error::Parse("TIFF offset out of bounds"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THUMBNAIL: &[u8] = &[0xff, 0xd8, 1, 2, 3, 0xff, 0xd9];
+
+    /// Builds an EXIF payload whose IFD0 holds an orientation entry and whose IFD1, if
+    /// `with_ifd1`, locates `THUMBNAIL` behind the IFDs.
+    fn exif_payload(big_endian: bool, with_ifd1: bool) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        // Tag, type, count and the value, which is left-justified in the four bytes
+        let entry = |tag: u16, kind: u16, value: u32| {
+            let mut entry = u16_bytes(tag).to_vec();
+            entry.extend(u16_bytes(kind));
+            entry.extend(u32_bytes(1));
+            if kind == 3 {
+                entry.extend(u16_bytes(value as u16));
+                entry.extend([0, 0]);
+            } else {
+                entry.extend(u32_bytes(value));
+            }
+            entry
+        };
+
+        let mut tiff = if big_endian { b"MM" } else { b"II" }.to_vec();
+        tiff.extend(u16_bytes(42));
+        tiff.extend(u32_bytes(8));
+
+        // IFD0 at offset 8 with a single entry, followed by the offset of IFD1 at 26
+        tiff.extend(u16_bytes(1));
+        tiff.extend(entry(0x0112, 3, 1));
+        tiff.extend(u32_bytes(if with_ifd1 { 26 } else { 0 }));
+
+        if with_ifd1 {
+            // IFD1 with three entries ends at 68, where the thumbnail starts
+            tiff.extend(u16_bytes(3));
+            tiff.extend(entry(0x0103, 3, 6));
+            tiff.extend(entry(TAG_JPEG_INTERCHANGE_FORMAT, 4, 68));
+            tiff.extend(entry(
+                TAG_JPEG_INTERCHANGE_FORMAT_LENGTH,
+                4,
+                THUMBNAIL.len() as u32,
+            ));
+            tiff.extend(u32_bytes(0));
+            tiff.extend(THUMBNAIL);
+            // Data after the thumbnail isn't part of it
+            tiff.extend([0xaa; 4]);
+        }
+
+        let mut payload = EXIF_IDENTIFIER.to_vec();
+        payload.extend(tiff);
+        payload
+    }
+
+    #[test]
+    fn thumbnail_is_located_by_ifd1() {
+        for big_endian in [false, true] {
+            let payload = exif_payload(big_endian, true);
+            assert_eq!(thumbnail(&payload).unwrap(), Some(THUMBNAIL));
+        }
+    }
+
+    #[test]
+    fn missing_ifd1_has_no_thumbnail() {
+        for big_endian in [false, true] {
+            let payload = exif_payload(big_endian, false);
+            assert_eq!(thumbnail(&payload).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn thumbnail_out_of_bounds_is_an_error() {
+        let mut payload = exif_payload(false, true);
+        payload.truncate(payload.len() - 6);
+        assert!(thumbnail(&payload).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+// Basis functions of the 1-D IDCT (A.3.3), C(u) / 2 * cos((2x + 1) * u * PI / 16) indexed by
+// frequency `u` and sample `x`, in fixed point with 12 fractional bits
+#[rustfmt::skip]
+const COSINES: [[i64; 8]; 8] = [
+    [1448,  1448,  1448,  1448,  1448,  1448,  1448,  1448],
+    [2009,  1703,  1138,   400,  -400, -1138, -1703, -2009],
+    [1892,   784,  -784, -1892, -1892,  -784,   784,  1892],
+    [1703,  -400, -2009, -1138,  1138,  2009,   400, -1703],
+    [1448, -1448, -1448,  1448,  1448, -1448, -1448,  1448],
+    [1138, -2009,   400,  1703, -1703,  -400,  2009, -1138],
+    [ 784, -1892,  1892,  -784,  -784,  1892, -1892,   784],
+    [ 400, -1138,  1703, -2009,  2009, -1703,  1138,  -400],
+];
+
+const FRACTIONAL_BITS: u32 = 12;
+
+/// Transforms a dequantized, row-major block of coefficients back to samples, applying the level
+/// shift and clamping the result to the range of 8-bit samples.
+///
+/// The 2-D IDCT is separable, so it is computed as 1-D IDCTs over the rows followed by the
+/// columns.
+pub fn idct_8x8(block: &[i32; 64]) -> [u8; 64] {
+    let mut rows = [0i64; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            rows[y * 8 + x] = (0..8)
+                .map(|u| COSINES[u][x] * block[y * 8 + u] as i64)
+                .sum();
+        }
+    }
+
+    let mut output = [0; 64];
+    for x in 0..8 {
+        for y in 0..8 {
+            let sum: i64 = (0..8).map(|v| COSINES[v][y] * rows[v * 8 + x]).sum();
+            // Both passes are scaled, round to nearest before dropping the fractional bits
+            let sample = (sum + (1 << (2 * FRACTIONAL_BITS - 1))) >> (2 * FRACTIONAL_BITS);
+            output[y * 8 + x] = (sample + 128).clamp(0, 255) as u8;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+    // Straight evaluation of the IDCT formula in A.3.3, with the same level shift and clamping
+    fn reference_idct(block: &[i32; 64]) -> [u8; 64] {
+        let scale = |u: usize| if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+        let mut output = [0; 64];
+        for y in 0..8 {
+            for x in 0..8 {
+                let mut sum = 0.0;
+                for v in 0..8 {
+                    for u in 0..8 {
+                        sum += scale(u)
+                            * scale(v)
+                            * block[v * 8 + u] as f64
+                            * ((2 * x + 1) as f64 * u as f64 * PI / 16.0).cos()
+                            * ((2 * y + 1) as f64 * v as f64 * PI / 16.0).cos();
+                    }
+                }
+                output[y * 8 + x] = (sum / 4.0 + 128.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn flat_block_gives_uniform_samples() {
+        for value in [0, 1, 64, 127, 128, 200, 254, 255] {
+            let mut block = [0; 64];
+            block[0] = 8 * (value - 128);
+            assert_eq!(idct_8x8(&block), [value as u8; 64], "DC for {value}");
+        }
+    }
+
+    #[test]
+    fn matches_float_reference_within_one() {
+        // Small linear congruential generator, keeps the blocks the same on every run
+        let mut seed = 0x2545_f491_u32;
+        let mut next = |range: i32| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as i32 % (2 * range + 1) - range
+        };
+
+        for _ in 0..200 {
+            let mut block = [0; 64];
+            block[0] = next(1024);
+            for coefficient in &mut block[1..] {
+                *coefficient = next(64);
+            }
+
+            let actual = idct_8x8(&block);
+            let expected = reference_idct(&block);
+            for (i, (&a, &e)) in actual.iter().zip(&expected).enumerate() {
+                assert!(
+                    (a as i32 - e as i32).abs() <= 1,
+                    "sample {i}: {a} vs {e} for {block:?}"
+                );
+            }
+        }
+    }
+}
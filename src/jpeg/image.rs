@@ -0,0 +1,119 @@
+use std::ops::Range;
+
+/// Layout of the samples of a decoded image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Three samples per pixel, in the order red, green, blue.
+    Rgb,
+    /// One sample per pixel.
+    Grayscale,
+    /// Four samples per pixel, in the order cyan, magenta, yellow, black. 0 means no ink.
+    Cmyk,
+}
+
+impl PixelFormat {
+    /// Returns the number of samples of each pixel.
+    pub fn samples_per_pixel(&self) -> usize {
+        match self {
+            Self::Rgb => 3,
+            Self::Grayscale => 1,
+            Self::Cmyk => 4,
+        }
+    }
+}
+
+/// Organization of the scans of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanLayout {
+    /// At least one sequential scan holds the blocks of several components.
+    Interleaved,
+    /// Every sequential scan holds the blocks of a single component, like all scans of
+    /// grayscale images.
+    NonInterleaved,
+    /// The image is refined over several progressive scans.
+    Progressive,
+}
+
+/// Decoded pixels of an image, stored interleaved line by line without padding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedImage {
+    pub width: u16,
+    pub height: u16,
+    pub pixels: Vec<u8>,
+    pub format: PixelFormat,
+    pub(crate) scan_layout: ScanLayout,
+}
+
+impl DecodedImage {
+    /// Returns how the scans of the source image were organized.
+    pub fn scan_layout(&self) -> ScanLayout {
+        self.scan_layout
+    }
+
+    /// Returns the samples of the pixel in column `x` and line `y`, or `None` if it lies outside
+    /// of the image.
+    pub fn pixel(&self, x: u16, y: u16) -> Option<&[u8]> {
+        let range = self.pixel_range(x, y)?;
+        self.pixels.get(range)
+    }
+
+    /// Like `pixel`, but allows changing the samples.
+    pub fn pixel_mut(&mut self, x: u16, y: u16) -> Option<&mut [u8]> {
+        let range = self.pixel_range(x, y)?;
+        self.pixels.get_mut(range)
+    }
+
+    fn pixel_range(&self, x: u16, y: u16) -> Option<Range<usize>> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let samples = self.format.samples_per_pixel();
+        let start = (y as usize * self.width as usize + x as usize) * samples;
+        Some(start..start + samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::builder::JpegBuilder;
+    use crate::jpeg::color::ycbcr_to_rgb;
+    use crate::jpeg::decoder::decode_bytes;
+
+    #[test]
+    fn scan_layout_tells_interleaved_scans_apart() {
+        let color = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1), (1, 1), (1, 1)])
+            .build();
+        let image = decode_bytes(&color).unwrap();
+        assert_eq!(image.scan_layout(), ScanLayout::Interleaved);
+
+        let gray = JpegBuilder::new(8, 8).build();
+        let image = decode_bytes(&gray).unwrap();
+        assert_eq!(image.scan_layout(), ScanLayout::NonInterleaved);
+    }
+
+    #[test]
+    fn pixel_returns_the_samples_at_a_position() {
+        // Blocks alternate between two colors from left to right
+        let data = JpegBuilder::new(16, 8)
+            .sampling_factors(&[(1, 1); 3])
+            .coefficients(|component, x, _| {
+                let mut block = [0; 64];
+                block[0] = 8 * ([[200, 100, 30], [50, 150, 250]][x][component] - 128);
+                block
+            })
+            .build();
+        let mut image = decode_bytes(&data).unwrap();
+        let expected = ycbcr_to_rgb(50, 150, 250);
+
+        assert_eq!(image.pixel(12, 5), Some(&expected[..]));
+        assert_eq!(image.pixel(15, 7), Some(&expected[..]));
+        assert_eq!(image.pixel(16, 0), None);
+        assert_eq!(image.pixel(0, 8), None);
+
+        image.pixel_mut(3, 2).unwrap().copy_from_slice(&[1, 2, 3]);
+        assert_eq!(image.pixel(3, 2), Some(&[1, 2, 3][..]));
+        assert_eq!(image.pixels[(2 * 16 + 3) * 3..][..3], [1, 2, 3]);
+    }
+}
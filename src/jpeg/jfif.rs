@@ -0,0 +1,55 @@
+use super::error::{Error, Result};
+use super::util::{read_u16_be, read_u8};
+
+const JFIF_IDENTIFIER: &[u8] = b"JFIF\0";
+
+/// Unit of the pixel density of a JFIF header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DensityUnits {
+    /// The densities only specify the pixel aspect ratio.
+    None,
+    DotsPerInch,
+    DotsPerCentimeter,
+}
+
+/// Contents of the JFIF APP0 segment (JFIF 1.02), apart from the uncompressed thumbnail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JfifHeader {
+    pub version_major: u8,
+    pub version_minor: u8,
+    pub density_units: DensityUnits,
+    pub x_density: u16,
+    pub y_density: u16,
+    pub thumbnail_width: u8,
+    pub thumbnail_height: u8,
+}
+
+/// Reads the JFIF header from the payload of an APP0 segment.
+///
+/// Returns `None` if the payload doesn't start with the `"JFIF\0"` identifier, like the APP0
+/// segments of JFIF extensions or Motion-JPEG (`"AVI1"`).
+pub fn header(app0: &[u8]) -> Result<Option<JfifHeader>> {
+    let mut reader = match app0.strip_prefix(JFIF_IDENTIFIER) {
+        Some(reader) => reader,
+        None => return Ok(None),
+    };
+
+    let version_major = read_u8(&mut reader)?;
+    let version_minor = read_u8(&mut reader)?;
+    let density_units = match read_u8(&mut reader)? {
+        0 => DensityUnits::None,
+        1 => DensityUnits::DotsPerInch,
+        2 => DensityUnits::DotsPerCentimeter,
+        _ => return Err(Error::Parse("Invalid JFIF density units")),
+    };
+
+    Ok(Some(JfifHeader {
+        version_major,
+        version_minor,
+        density_units,
+        x_density: read_u16_be(&mut reader)?,
+        y_density: read_u16_be(&mut reader)?,
+        thumbnail_width: read_u8(&mut reader)?,
+        thumbnail_height: read_u8(&mut reader)?,
+    }))
+}
@@ -1,8 +1,10 @@
 use super::error::Error;
 use super::error::Result;
 use super::util::{read_u16_be, read_u8};
+use std::io;
 use std::io::Read;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Marker {
     StartOfImage,
     ApplicationSegment(u8, u16),
@@ -11,11 +13,19 @@ pub enum Marker {
     StartOfFrame(u8, u16),
     DefineHuffmanTable(u16),
     StartOfScan(u16),
+    DefineRestartInterval(u16),
+    Restart(u8),
     EndOfImage,
 }
 
 impl Marker {
     pub fn from_reader<R: Read>(reader: &mut R) -> Result<Self> {
+        let code = Self::read_code(reader)?;
+        Self::from_code(code, reader)
+    }
+
+    /// Skips to the next marker and returns its code, the byte following `0xff`.
+    pub(crate) fn read_code<R: Read>(reader: &mut R) -> Result<u8> {
         loop {
             // This should be an error as the JPEG spec doesn't allow extraneous data between marker segments.
             // libjpeg allows this though and there are images in the wild utilising it, so we are
@@ -38,27 +48,189 @@ impl Marker {
 
             // Section B.1.1.4
             if byte != 0x00 && byte != 0xff {
-                return match byte {
-                    0xd8 => Ok(Self::StartOfImage),
-                    0xe0 => Ok(Self::ApplicationSegment(0, read_u16_be(reader)?)),
-                    0xe1 => Ok(Self::ApplicationSegment(1, read_u16_be(reader)?)),
-                    0xe2 => Ok(Self::ApplicationSegment(2, read_u16_be(reader)?)),
-                    0xe3 => Ok(Self::ApplicationSegment(3, read_u16_be(reader)?)),
-                    0xe4 => Ok(Self::ApplicationSegment(4, read_u16_be(reader)?)),
-                    0xe5 => Ok(Self::ApplicationSegment(5, read_u16_be(reader)?)),
-                    0xe6 => Ok(Self::ApplicationSegment(6, read_u16_be(reader)?)),
-                    0xe7 => Ok(Self::ApplicationSegment(7, read_u16_be(reader)?)),
-                    0xe8 => Ok(Self::ApplicationSegment(8, read_u16_be(reader)?)),
-                    0xe9 => Ok(Self::ApplicationSegment(9, read_u16_be(reader)?)),
-                    0xfe => Ok(Self::Comment(read_u16_be(reader)?)),
-                    0xdb => Ok(Self::DefineQuantizationTable(read_u16_be(reader)?)),
-                    0xc0 => Ok(Self::StartOfFrame(0, read_u16_be(reader)?)),
-                    0xc4 => Ok(Self::DefineHuffmanTable(read_u16_be(reader)?)),
-                    0xda => Ok(Self::StartOfScan(read_u16_be(reader)?)),
-                    0xd9 => Ok(Self::EndOfImage),
-                    _ => Err(Error::Unsupported("Unsupported marker")),
-                };
+                return Ok(byte);
+            }
+        }
+    }
+
+    /// Reads the rest of the marker with the code `byte`, which is the segment length for
+    /// markers that start a segment.
+    pub(crate) fn from_code<R: Read>(byte: u8, reader: &mut R) -> Result<Self> {
+        match byte {
+            0xd8 => Ok(Self::StartOfImage),
+            // APPn markers, `n` is the low nibble
+            0xe0..=0xef => Ok(Self::ApplicationSegment(byte & 0x0f, read_u16_be(reader)?)),
+            0xfe => Ok(Self::Comment(read_u16_be(reader)?)),
+            0xdb => Ok(Self::DefineQuantizationTable(read_u16_be(reader)?)),
+            0xc4 => Ok(Self::DefineHuffmanTable(read_u16_be(reader)?)),
+            // Table B.1: SOFn markers, except for DHT (0xc4), JPG (0xc8) and DAC (0xcc)
+            // which share the range. `n` selects the encoding process.
+            0xc0..=0xcf if byte != 0xc8 && byte != 0xcc => {
+                Ok(Self::StartOfFrame(byte & 0x0f, read_u16_be(reader)?))
+            }
+            0xda => Ok(Self::StartOfScan(read_u16_be(reader)?)),
+            0xdd => {
+                // B.2.4.4
+                if read_u16_be(reader)? != 4 {
+                    return Err(Error::Parse("Invalid restart interval segment length"));
+                }
+                Ok(Self::DefineRestartInterval(read_u16_be(reader)?))
+            }
+            // RSTn markers stand alone without a length field, `n` is the restart
+            // interval index modulo 8
+            0xd0..=0xd7 => Ok(Self::Restart(byte - 0xd0)),
+            0xd9 => Ok(Self::EndOfImage),
+            _ => Err(Error::Unsupported("Unsupported marker")),
+        }
+    }
+
+    /// Checks the segment length against the smallest valid length for the marker (Annex B),
+    /// which includes the two bytes of the length field itself.
+    pub fn check_size(&self) -> Result<()> {
+        let (size, minimum_size, message) = match *self {
+            // The length of DRI segments is checked when reading the marker
+            Self::StartOfImage
+            | Self::DefineRestartInterval(_)
+            | Self::Restart(_)
+            | Self::EndOfImage => return Ok(()),
+            Self::ApplicationSegment(_, size) => (size, 2, "Application segment too small"),
+            Self::Comment(size) => (size, 2, "Comment segment too small"),
+            // Precision and destination, followed by 64 elements
+            Self::DefineQuantizationTable(size) => (size, 67, "Quantization table too small"),
+            // Frame header with a single component
+            Self::StartOfFrame(_, size) => (size, 11, "Frame header too small"),
+            // Class and destination, followed by 16 code length counts
+            Self::DefineHuffmanTable(size) => (size, 19, "Huffman table too small"),
+            // Scan header with a single component
+            Self::StartOfScan(size) => (size, 8, "Scan header too small"),
+        };
+
+        if size < minimum_size {
+            Err(Error::Parse(message))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reader which counts the bytes read through it.
+struct CountingReader<R: Read> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+/// Iterates over the markers of a JPEG stream, together with the offset of each marker in the
+/// stream.
+///
+/// Segment payloads and entropy-coded data are skipped, so tools can walk the structure of a
+/// file without decoding it. Iteration ends after the EOI marker or the first error.
+pub struct MarkerIter<R: Read> {
+    reader: CountingReader<R>,
+    done: bool,
+}
+
+impl<R: Read> MarkerIter<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: CountingReader {
+                inner: reader,
+                position: 0,
+            },
+            done: false,
+        }
+    }
+
+    fn read_marker(&mut self) -> Result<(Marker, u64)> {
+        let code = Marker::read_code(&mut self.reader)?;
+        // The marker starts with the `0xff` directly in front of its code
+        let offset = self.reader.position - 2;
+        let marker = Marker::from_code(code, &mut self.reader)?;
+
+        let payload = match marker {
+            Marker::ApplicationSegment(_, size)
+            | Marker::Comment(size)
+            | Marker::DefineQuantizationTable(size)
+            | Marker::StartOfFrame(_, size)
+            | Marker::DefineHuffmanTable(size)
+            | Marker::StartOfScan(size) => {
+                size.checked_sub(2)
+                    .ok_or(Error::Parse("Segment length too small"))? as u64
             }
+            Marker::StartOfImage
+            | Marker::DefineRestartInterval(_)
+            | Marker::Restart(_)
+            | Marker::EndOfImage => 0,
+        };
+        if io::copy(&mut self.reader.by_ref().take(payload), &mut io::sink())? < payload {
+            return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
         }
+
+        Ok((marker, offset))
+    }
+}
+
+impl<R: Read> Iterator for MarkerIter<R> {
+    type Item = Result<(Marker, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.read_marker();
+        self.done = matches!(result, Err(_) | Ok((Marker::EndOfImage, _)));
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker_iter_walks_sample_image() {
+        let data: &[u8] = include_bytes!("../../samples/lenna.jpg");
+        let markers = MarkerIter::new(data).collect::<Result<Vec<_>>>().unwrap();
+
+        assert_eq!(
+            markers,
+            [
+                (Marker::StartOfImage, 0),
+                (Marker::ApplicationSegment(0, 16), 2),
+                (Marker::Comment(64), 20),
+                (Marker::DefineQuantizationTable(67), 86),
+                (Marker::DefineQuantizationTable(67), 155),
+                (Marker::StartOfFrame(0, 17), 224),
+                (Marker::DefineHuffmanTable(31), 243),
+                (Marker::DefineHuffmanTable(181), 276),
+                (Marker::DefineHuffmanTable(31), 459),
+                (Marker::DefineHuffmanTable(181), 492),
+                (Marker::StartOfScan(12), 675),
+                (Marker::EndOfImage, data.len() as u64 - 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn undersized_frame_and_scan_headers_are_rejected() {
+        assert!(Marker::StartOfFrame(0, 11).check_size().is_ok());
+        assert!(matches!(
+            Marker::StartOfFrame(0, 10).check_size(),
+            Err(Error::Parse("Frame header too small"))
+        ));
+
+        assert!(Marker::StartOfScan(8).check_size().is_ok());
+        assert!(matches!(
+            Marker::StartOfScan(7).check_size(),
+            Err(Error::Parse("Scan header too small"))
+        ));
     }
 }
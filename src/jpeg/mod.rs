@@ -1,5 +1,29 @@
+pub mod adobe;
+pub mod bitreader;
+pub mod block;
+#[cfg(any(test, feature = "test-util"))]
+pub mod builder;
+pub mod capabilities;
+pub mod color;
 pub mod decoder;
+pub mod entropy;
 pub mod error;
+pub mod exif;
 pub mod huffman_tree;
+pub mod idct;
+pub mod image;
+pub mod jfif;
 pub mod marker;
+pub mod mpf;
+pub mod probe;
+pub mod reader;
+pub mod structure;
+pub mod tables;
+pub mod upsampler;
 pub mod util;
+
+pub use capabilities::{capabilities, Capabilities};
+pub use color::{cmyk_to_rgb, rgb_to_bgr, rgb_to_grayscale};
+pub use decoder::decode_bytes;
+pub use image::{DecodedImage, PixelFormat, ScanLayout};
+pub use probe::is_progressive;
@@ -0,0 +1,54 @@
+use super::error::{Error, Result};
+use super::exif::Tiff;
+
+const MPF_IDENTIFIER: &[u8] = b"MPF\0";
+
+// MP Index IFD tag listing all images (CIPA DC-007, section 5.2.3.3)
+const TAG_MP_ENTRY: u16 = 0xb002;
+
+/// Location of an image in a Multi-Picture (MPO) file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MpEntry {
+    /// Offset relative to the start of the MP header, which is the TIFF header following the
+    /// `"MPF\0"` identifier. The first image always has offset 0.
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Reads the image list from the payload of an MPF APP2 segment.
+///
+/// Returns `None` if the payload isn't an MP header or doesn't contain an MP Index IFD.
+pub fn entries(app2: &[u8]) -> Result<Option<Vec<MpEntry>>> {
+    let tiff = match app2.strip_prefix(MPF_IDENTIFIER) {
+        Some(tiff) => Tiff::new(tiff)?,
+        None => return Ok(None),
+    };
+
+    let ifd = tiff.u32_at(4)? as usize;
+    let entries_count = tiff.u16_at(ifd)? as usize;
+    for i in 0..entries_count {
+        let entry = ifd + 2 + i * 12;
+        if tiff.u16_at(entry)? != TAG_MP_ENTRY {
+            continue;
+        }
+
+        // Every MP entry is 16 bytes: attributes, size, offset and two dependent image entries
+        let mp_entries_size = tiff.u32_at(entry + 4)? as usize;
+        let mp_entries = tiff.u32_at(entry + 8)? as usize;
+        if !mp_entries_size.is_multiple_of(16) {
+            return Err(Error::Parse("Invalid MP entry size"));
+        }
+
+        let mut images = Vec::new();
+        for j in 0..mp_entries_size / 16 {
+            let mp_entry = mp_entries + j * 16;
+            images.push(MpEntry {
+                size: tiff.u32_at(mp_entry + 4)? as usize,
+                offset: tiff.u32_at(mp_entry + 8)? as usize,
+            });
+        }
+        return Ok(Some(images));
+    }
+
+    Ok(None)
+}
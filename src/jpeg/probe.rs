@@ -0,0 +1,74 @@
+use super::error::{Error, Result};
+use super::marker::Marker;
+use super::util::read_u16_be;
+use std::io;
+
+/// Checks whether the JPEG in `bytes` uses a progressive encoding process.
+///
+/// Only the marker segments in front of the frame header are walked, no tables or headers are
+/// parsed, so this is cheap enough to triage large batches of files.
+pub fn is_progressive(bytes: &[u8]) -> Result<bool> {
+    let mut reader = bytes;
+
+    loop {
+        match Marker::read_code(&mut reader)? {
+            // SOF2, SOF6, SOF10 and SOF14 are the progressive processes (Table B.1)
+            0xc2 | 0xc6 | 0xca | 0xce => return Ok(true),
+            // DHT, JPG and DAC share the SOF range
+            0xc4 | 0xc8 | 0xcc => skip_segment(&mut reader)?,
+            0xc0..=0xcf => return Ok(false),
+            // TEM, RSTn and SOI don't have a length
+            0x01 | 0xd0..=0xd8 => {}
+            0xd9 | 0xda => return Err(Error::Parse("No frame header found")),
+            _ => skip_segment(&mut reader)?,
+        }
+    }
+}
+
+fn skip_segment(reader: &mut &[u8]) -> Result<()> {
+    let size = read_u16_be(reader)? as usize;
+    if size < 2 {
+        return Err(Error::Parse("Segment length too small"));
+    }
+    if size - 2 > reader.len() {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    *reader = &reader[size - 2..];
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::builder::JpegBuilder;
+
+    /// A builder stream with an APP1 and a COM segment and fill bytes in front of the frame
+    /// header, whose marker is changed to `SOFn`.
+    fn image_with_sof(n: u8) -> Vec<u8> {
+        let data = JpegBuilder::new(8, 8).build();
+        let sof = data
+            .windows(2)
+            .position(|bytes| bytes == [0xff, 0xc0])
+            .unwrap();
+
+        let mut patched = data[..2].to_vec();
+        patched.extend([0xff, 0xe1, 0x00, 0x06, b'E', b'x', 0xff, 0xc2]);
+        patched.extend([0xff, 0xfe, 0x00, 0x04, 0xc2, 0x00]);
+        patched.extend(&data[2..sof]);
+        patched.extend([0xff, 0xff, 0xff, 0xc0 + n]);
+        patched.extend(&data[sof + 2..]);
+        patched
+    }
+
+    #[test]
+    fn baseline_and_progressive_frames_are_told_apart() {
+        assert!(!is_progressive(&image_with_sof(0)).unwrap());
+        assert!(!is_progressive(&image_with_sof(1)).unwrap());
+        assert!(is_progressive(&image_with_sof(2)).unwrap());
+    }
+
+    #[test]
+    fn missing_frame_header_is_an_error() {
+        assert!(is_progressive(&[0xff, 0xd8, 0xff, 0xd9]).is_err());
+    }
+}
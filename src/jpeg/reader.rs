@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+
+/// Reader which supports looking ahead and giving bytes back to the stream.
+///
+/// Segment boundaries in a JPEG stream can only be verified by looking at the bytes that follow
+/// them, so the decoder needs to be able to undo reads.
+pub struct PushbackReader<R: Read> {
+    inner: R,
+    pending: VecDeque<u8>,
+    recording: bool,
+    // Bytes consumed since `record` was called, in the order they were read
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> PushbackReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            pending: VecDeque::new(),
+            recording: false,
+            recorded: Vec::new(),
+        }
+    }
+
+    /// Replaces the underlying reader, discarding any bytes that were peeked or given back.
+    pub fn reset(&mut self, inner: R) {
+        self.inner = inner;
+        self.pending.clear();
+        self.stop_recording();
+    }
+
+    /// Starts keeping the bytes consumed from now on, so `rewind` can give them back.
+    pub fn record(&mut self) {
+        self.recording = true;
+        self.recorded.clear();
+    }
+
+    /// Stops keeping consumed bytes and drops the ones kept so far.
+    pub fn stop_recording(&mut self) {
+        self.recording = false;
+        self.recorded.clear();
+    }
+
+    /// Gives back all bytes consumed since `record` was called, so they are read again.
+    pub fn rewind(&mut self) {
+        for byte in self.recorded.drain(..).rev() {
+            self.pending.push_front(byte);
+        }
+    }
+
+    /// Returns the next byte without consuming it, or `None` at the end of the stream.
+    pub fn peek_u8(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() {
+            self.fill_byte()?;
+        }
+        Ok(self.pending.front().copied())
+    }
+
+    /// Returns up to `count` of the next bytes without consuming them, fewer at the end of the
+    /// stream.
+    pub fn peek(&mut self, count: usize) -> io::Result<Vec<u8>> {
+        while self.pending.len() < count && self.fill_byte()? {}
+        Ok(self.pending.iter().take(count).copied().collect())
+    }
+
+    /// Returns the offset of the next occurrence of `pattern` without consuming any bytes, or
+    /// `None` if the stream ends before it.
+    pub fn find(&mut self, pattern: &[u8]) -> io::Result<Option<usize>> {
+        let mut offset = 0;
+        loop {
+            while self.pending.len() < offset + pattern.len() {
+                if !self.fill_byte()? {
+                    return Ok(None);
+                }
+            }
+            if self
+                .pending
+                .range(offset..offset + pattern.len())
+                .eq(pattern)
+            {
+                return Ok(Some(offset));
+            }
+            offset += 1;
+        }
+    }
+
+    /// Reads one more byte into the peeked bytes, returns `false` at the end of the stream.
+    fn fill_byte(&mut self) -> io::Result<bool> {
+        // Like `read_exact`, retry reads that were interrupted before any data arrived
+        let mut byte = [0];
+        loop {
+            match self.inner.read(&mut byte) {
+                Ok(0) => return Ok(false),
+                Ok(_) => {
+                    self.pending.push_back(byte[0]);
+                    return Ok(true);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Puts `bytes` back in front of the stream, so they are returned by the next reads. These
+    /// have to be the bytes consumed last.
+    pub fn unread(&mut self, bytes: &[u8]) {
+        if self.recording {
+            self.recorded
+                .truncate(self.recorded.len().saturating_sub(bytes.len()));
+        }
+        for byte in bytes.iter().rev() {
+            self.pending.push_front(*byte);
+        }
+    }
+}
+
+impl<R: Read> Read for PushbackReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = if self.pending.is_empty() {
+            self.inner.read(buf)?
+        } else {
+            let count = buf.len().min(self.pending.len());
+            for (dst, src) in buf.iter_mut().zip(self.pending.drain(..count)) {
+                *dst = src;
+            }
+            count
+        };
+
+        if self.recording {
+            self.recorded.extend_from_slice(&buf[..count]);
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jpeg::decoder::{decode_bytes, Decoder};
+    use std::io::{self, Read};
+
+    /// Reader which returns a single byte per call, after being interrupted once.
+    struct TrickleReader<'a> {
+        data: &'a [u8],
+        interrupted: bool,
+    }
+
+    impl Read for TrickleReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            if self.data.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[0];
+            self.data = &self.data[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn short_and_interrupted_reads_decode_the_same_image() {
+        let data: &[u8] = include_bytes!("../../samples/lenna.jpg");
+        let expected = decode_bytes(data).unwrap();
+
+        let mut decoder = Decoder::new(TrickleReader {
+            data,
+            interrupted: false,
+        });
+        assert_eq!(decoder.decode().unwrap(), expected);
+    }
+}
@@ -0,0 +1,67 @@
+/// Outline of the marker segments of a file and the headers and tables they contain, collected
+/// while parsing.
+#[derive(Debug, Clone, Default)]
+pub struct JpegStructure {
+    entries: Vec<(usize, String)>,
+}
+
+impl JpegStructure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a line at nesting level `depth`, below the last line with a lower depth.
+    pub fn push(&mut self, depth: usize, line: impl Into<String>) {
+        self.entries.push((depth, line.into()));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Formats the structure as a tree with one line per entry, indented by its depth.
+    pub fn to_tree_string(&self) -> String {
+        let mut tree = String::new();
+        for (depth, line) in &self.entries {
+            for _ in 0..*depth {
+                tree.push('\t');
+            }
+            tree.push_str(line);
+            tree.push('\n');
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::builder::JpegBuilder;
+    use crate::jpeg::decoder::Decoder;
+
+    #[test]
+    fn entries_are_indented_by_depth() {
+        let mut structure = JpegStructure::new();
+        structure.push(0, "Start of Image");
+        structure.push(1, "Components: 1");
+        structure.push(2, "Component id: 1");
+
+        assert_eq!(
+            structure.to_tree_string(),
+            "Start of Image\n\tComponents: 1\n\t\tComponent id: 1\n"
+        );
+    }
+
+    #[test]
+    fn tree_lists_the_frame_header() {
+        let data = JpegBuilder::new(8, 8)
+            .sampling_factors(&[(1, 1), (1, 1), (1, 1)])
+            .build();
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.decode().unwrap();
+
+        let tree = decoder.structure().to_tree_string();
+        assert!(tree.contains("\nStart of Frame(0) - 17\n\tEncoding process: Baseline DCT\n"));
+        assert!(tree.contains("\tComponents: 3\n"));
+    }
+}
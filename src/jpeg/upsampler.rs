@@ -0,0 +1,158 @@
+/// Scales subsampled component planes up to the resolution of the image.
+///
+/// `Decoder::decode` passes whole planes. `Decoder::decode_rows` converts lines while they are
+/// decoded and passes horizontal bands of whole MCU lines instead, which include the MCU line
+/// above and below the lines it converts where the plane has them. The output of an upsampler is
+/// only the same in both cases if each output sample depends on source samples at most one MCU
+/// line, which is at least 8 source lines, away. Filters reaching further produce seams between
+/// the bands.
+pub trait Upsampler {
+    /// Scales `plane`, which is `width` samples wide and `height` lines high, to
+    /// `target_width` x `target_height` samples.
+    fn upsample(
+        &self,
+        plane: &[u8],
+        width: usize,
+        height: usize,
+        target_width: usize,
+        target_height: usize,
+    ) -> Vec<u8>;
+}
+
+/// Replicates every sample, which is fast but produces blocky edges.
+pub struct NearestUpsampler;
+
+impl Upsampler for NearestUpsampler {
+    fn upsample(
+        &self,
+        plane: &[u8],
+        width: usize,
+        height: usize,
+        target_width: usize,
+        target_height: usize,
+    ) -> Vec<u8> {
+        let mut output = Vec::with_capacity(target_width * target_height);
+        for y in 0..target_height {
+            let line = &plane[(y * height / target_height) * width..];
+            output.extend((0..target_width).map(|x| line[x * width / target_width]));
+        }
+        output
+    }
+}
+
+/// Interpolates linearly between the centers of neighboring samples, like the "fancy"
+/// upsampling of libjpeg.
+pub struct TriangleUpsampler;
+
+impl Upsampler for TriangleUpsampler {
+    fn upsample(
+        &self,
+        plane: &[u8],
+        width: usize,
+        height: usize,
+        target_width: usize,
+        target_height: usize,
+    ) -> Vec<u8> {
+        // Position of a target sample center in the source plane, as the two closest samples
+        // and the weight of the second one
+        let neighbors = |target: usize, size: usize, target_size: usize| {
+            let position = (target as f32 + 0.5) * size as f32 / target_size as f32 - 0.5;
+            let position = position.clamp(0.0, (size - 1) as f32);
+            let first = position as usize;
+            let second = (first + 1).min(size - 1);
+            (first, second, position - first as f32)
+        };
+
+        let mut output = Vec::with_capacity(target_width * target_height);
+        for y in 0..target_height {
+            let (top, bottom, vertical_weight) = neighbors(y, height, target_height);
+            for x in 0..target_width {
+                let (left, right, horizontal_weight) = neighbors(x, width, target_width);
+                let sample = |x: usize, y: usize| plane[y * width + x] as f32;
+
+                let upper = sample(left, top) * (1.0 - horizontal_weight)
+                    + sample(right, top) * horizontal_weight;
+                let lower = sample(left, bottom) * (1.0 - horizontal_weight)
+                    + sample(right, bottom) * horizontal_weight;
+                let value = upper * (1.0 - vertical_weight) + lower * vertical_weight;
+                output.push(value.round() as u8);
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::builder::JpegBuilder;
+    use crate::jpeg::decode_bytes;
+    use crate::jpeg::decoder::Decoder;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn nearest_replicates_samples() {
+        let plane = [10, 20, 30, 40];
+        assert_eq!(
+            NearestUpsampler.upsample(&plane, 2, 2, 4, 4),
+            [10, 10, 20, 20, 10, 10, 20, 20, 30, 30, 40, 40, 30, 30, 40, 40]
+        );
+    }
+
+    #[test]
+    fn triangle_interpolates_between_sample_centers() {
+        // Edge samples are clamped, inner ones weigh their neighbors 1:3
+        assert_eq!(
+            TriangleUpsampler.upsample(&[0, 100], 2, 1, 4, 1),
+            [0, 25, 75, 100]
+        );
+        assert_eq!(
+            TriangleUpsampler.upsample(&[0, 100], 1, 2, 1, 4),
+            [0, 25, 75, 100]
+        );
+        assert_eq!(TriangleUpsampler.upsample(&[7; 4], 2, 2, 4, 4), [7; 16]);
+    }
+
+    /// Plane size and target size of a call.
+    type Call = (usize, usize, usize, usize);
+
+    /// Upsampler which records the sizes it was called with and passes them on.
+    struct RecordingUpsampler {
+        calls: Rc<RefCell<Vec<Call>>>,
+    }
+
+    impl Upsampler for RecordingUpsampler {
+        fn upsample(
+            &self,
+            plane: &[u8],
+            width: usize,
+            height: usize,
+            target_width: usize,
+            target_height: usize,
+        ) -> Vec<u8> {
+            assert_eq!(plane.len(), width * height);
+            self.calls
+                .borrow_mut()
+                .push((width, height, target_width, target_height));
+            NearestUpsampler.upsample(plane, width, height, target_width, target_height)
+        }
+    }
+
+    #[test]
+    fn decoder_uses_the_custom_upsampler() {
+        let data = JpegBuilder::new(33, 21)
+            .sampling_factors(&[(2, 2), (1, 1), (1, 1)])
+            .build();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_upsampler(Box::new(RecordingUpsampler {
+            calls: calls.clone(),
+        }));
+
+        let image = decoder.decode().unwrap();
+        assert_eq!(image.pixels, decode_bytes(&data).unwrap().pixels);
+        // Both chroma planes of 3x2 MCUs, luma isn't subsampled
+        assert_eq!(*calls.borrow(), [(24, 16, 48, 32); 2]);
+    }
+}
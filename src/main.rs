@@ -1,4 +1,4 @@
-mod jpeg;
+use jpeg_codec::jpeg;
 
 use std::fs::File;
 use std::io::BufReader;
@@ -6,5 +6,7 @@ use std::io::BufReader;
 fn main() {
     let file = File::open("samples/lenna.jpg").expect("Failed to open file!");
     let mut decoder = jpeg::decoder::Decoder::new(BufReader::new(file));
-    decoder.decode().expect("Could not decode");
+    let result = decoder.decode();
+    print!("{}", decoder.structure().to_tree_string());
+    result.expect("Could not decode");
 }